@@ -0,0 +1,263 @@
+use crate::beam_utils::{ControlPoint, PatientBox, TissueBox, compute_surface_entry};
+use crate::nifti::invalid_data;
+use crate::vector::Vector;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::io;
+
+const ARC_CONTROL_POINT_SPACING: f32 = 10.0;
+const ARC_MIN_SWEEP: f32 = 30.0;
+
+// A continuous gantry rotation around the tumour, delivered as a set of
+// discrete control points spaced `spacing` degrees apart
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "ArcFields")]
+pub struct GantryArc {
+    pub start_angle: f32,
+    pub stop_angle: f32,
+    pub spacing: f32,
+    pub couch_angle: f32,
+    pub weights: Vec<f32>,
+}
+
+// An arc as stored in a plan, checked before it becomes a GantryArc
+#[derive(Deserialize)]
+struct ArcFields {
+    start_angle: f32,
+    stop_angle: f32,
+    spacing: f32,
+    couch_angle: f32,
+    weights: Vec<f32>,
+}
+
+impl TryFrom<ArcFields> for GantryArc {
+    type Error = io::Error;
+
+    fn try_from(fields: ArcFields) -> io::Result<GantryArc> {
+        let arc = GantryArc {
+            start_angle: fields.start_angle,
+            stop_angle: fields.stop_angle,
+            spacing: fields.spacing,
+            couch_angle: fields.couch_angle,
+            weights: fields.weights,
+        };
+        arc.validate()?;
+        if arc.weights.len() != arc.n_control_points() {
+            return Err(invalid_data(format!(
+                "arc has {} weights for {} control points",
+                arc.weights.len(),
+                arc.n_control_points()
+            )));
+        }
+        Ok(arc)
+    }
+}
+
+impl GantryArc {
+    pub fn new(
+        start_angle: f32,
+        stop_angle: f32,
+        spacing: f32,
+        couch_angle: f32,
+    ) -> io::Result<GantryArc> {
+        let mut arc = GantryArc {
+            start_angle: start_angle.rem_euclid(360.0),
+            stop_angle: stop_angle.rem_euclid(360.0),
+            spacing,
+            couch_angle,
+            weights: vec![],
+        };
+        arc.validate()?;
+        arc.resize_weights();
+        Ok(arc)
+    }
+
+    // Random couch angles allow non-coplanar arcs
+    pub fn random() -> GantryArc {
+        let mut rng = crate::random::rng();
+        let start: f32 = rng.random_range(0.0..360.0);
+        let sweep: f32 = rng.random_range(ARC_MIN_SWEEP..360.0);
+        let couch: f32 = rng.random_range(-90.0..90.0);
+        let mut arc = GantryArc {
+            start_angle: start,
+            stop_angle: (start + sweep).rem_euclid(360.0),
+            spacing: ARC_CONTROL_POINT_SPACING,
+            couch_angle: couch,
+            weights: vec![],
+        };
+        arc.resize_weights();
+        arc
+    }
+
+    // Spacing must be positive so the arc has a finite number of control
+    // points, and equal start and stop angles leave nothing to sweep
+    pub fn validate(&self) -> io::Result<()> {
+        if !self.spacing.is_finite() || self.spacing <= 0.0 {
+            return Err(invalid_data(format!(
+                "arc control point spacing must be positive, got {}",
+                self.spacing
+            )));
+        }
+        let angles = [self.start_angle, self.stop_angle, self.couch_angle];
+        if angles.iter().any(|angle| !angle.is_finite()) {
+            return Err(invalid_data("arc angles must be finite"));
+        }
+        if self.start_angle.rem_euclid(360.0) == self.stop_angle.rem_euclid(360.0) {
+            return Err(invalid_data("arc start and stop angles are equal"));
+        }
+        Ok(())
+    }
+
+    // Arcs always rotate clockwise from start to stop
+    pub fn sweep(&self) -> f32 {
+        let sweep = (self.stop_angle - self.start_angle).rem_euclid(360.0);
+        if sweep == 0.0 { 360.0 } else { sweep }
+    }
+
+    pub fn n_control_points(&self) -> usize {
+        (self.sweep() / self.spacing).floor() as usize + 1
+    }
+
+    pub fn gantry_angles(&self) -> Vec<f32> {
+        (0..self.n_control_points())
+            .map(|i| (self.start_angle + i as f32 * self.spacing).rem_euclid(360.0))
+            .collect()
+    }
+
+    pub fn control_points(
+        &self,
+        tumour: &TissueBox,
+        patient_box: &PatientBox,
+    ) -> Vec<ControlPoint> {
+        let iso = tumour.centre();
+        self.gantry_angles()
+            .iter()
+            .zip(self.weights.iter())
+            .map(|(gantry, weight)| ControlPoint {
+                entry: compute_surface_entry(
+                    &iso,
+                    &Vector::from_gantry_couch(*gantry, self.couch_angle),
+                    patient_box,
+                ),
+                aim: iso,
+                weight: *weight,
+            })
+            .collect()
+    }
+
+    pub fn crossover(&self, p2: &GantryArc, alpha: f32) -> GantryArc {
+        let mut child = GantryArc {
+            start_angle: blend_angle(self.start_angle, p2.start_angle, alpha),
+            stop_angle: blend_angle(self.stop_angle, p2.stop_angle, alpha),
+            spacing: self.spacing,
+            couch_angle: alpha * self.couch_angle + (1.0 - alpha) * p2.couch_angle,
+            weights: vec![],
+        };
+        child.weights = self
+            .weights
+            .iter()
+            .enumerate()
+            .map(|(i, w)| match p2.weights.get(i) {
+                Some(w2) => alpha * w + (1.0 - alpha) * w2,
+                None => *w,
+            })
+            .collect();
+        child.resize_weights();
+        child
+    }
+
+    // Angle perturbations are in degrees, weight perturbations are
+    // relative to the mean control point weight
+    pub fn mutate(&mut self, mutation_bound: f32) {
+        let mut rng = crate::random::rng();
+        self.start_angle = (self.start_angle + rng.random_range(-mutation_bound..mutation_bound))
+            .rem_euclid(360.0);
+        self.stop_angle =
+            (self.stop_angle + rng.random_range(-mutation_bound..mutation_bound)).rem_euclid(360.0);
+        self.couch_angle = (self.couch_angle + rng.random_range(-mutation_bound..mutation_bound))
+            .clamp(-90.0, 90.0);
+        let mean_weight = self.weights.iter().sum::<f32>() / self.weights.len().max(1) as f32;
+        for weight in &mut self.weights {
+            let draw: f32 = rng.random_range(-0.5..0.5);
+            *weight = (*weight + draw * mean_weight).max(0.0);
+        }
+        self.resize_weights();
+    }
+
+    // Keeps one weight per control point after the angles changed
+    fn resize_weights(&mut self) {
+        let n_points = self.n_control_points();
+        let mean_weight = if self.weights.is_empty() {
+            1.0 / n_points as f32
+        } else {
+            self.weights.iter().sum::<f32>() / self.weights.len() as f32
+        };
+        self.weights.resize(n_points, mean_weight);
+    }
+}
+
+// Gantry angle `alpha` of the way from `b` to `a` along the shorter way
+// round, so 350 and 10 blend through 0 rather than 180
+fn blend_angle(a: f32, b: f32, alpha: f32) -> f32 {
+    let difference = (a - b + 180.0).rem_euclid(360.0) - 180.0;
+    (b + alpha * difference).rem_euclid(360.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beam_utils::TissueType;
+
+    #[test]
+    fn test_arc_control_points() {
+        let patient = PatientBox::new(40, 40, 40);
+        let tumour = TissueBox {
+            x: 20,
+            y: 20,
+            z: 20,
+            x_width: 4,
+            y_width: 4,
+            z_width: 4,
+            tissue_type: Some(TissueType::Tumour),
+        };
+        let arc = GantryArc::new(350.0, 90.0, 10.0, 0.0).unwrap();
+        assert_eq!(arc.n_control_points(), 11);
+        let points = arc.control_points(&tumour, &patient);
+        assert_eq!(points.len(), 11);
+        // Gantry 0 enters from the anterior face, gantry 90 from the
+        // patient's left
+        assert!(points[1].entry.y.abs() < 1e-3);
+        assert!((points[10].entry.x - 40.0).abs() < 1e-3);
+        let total: f32 = points.iter().map(|p| p.weight).sum();
+        assert!((total - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_arc_crossover_wraps() {
+        let a1 = GantryArc::new(350.0, 80.0, 10.0, 0.0).unwrap();
+        let a2 = GantryArc::new(10.0, 100.0, 10.0, 20.0).unwrap();
+        let child = a1.crossover(&a2, 0.5);
+        assert!(child.start_angle.abs() < 1e-4 || (child.start_angle - 360.0).abs() < 1e-4);
+        assert!((child.stop_angle - 90.0).abs() < 1e-4);
+        assert!((child.couch_angle - 10.0).abs() < 1e-4);
+        assert_eq!(child.weights.len(), child.n_control_points());
+        let child = a1.crossover(&a2, 0.75);
+        assert!((child.start_angle - 355.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_arc_validation() {
+        assert!(GantryArc::new(0.0, 90.0, 0.0, 0.0).is_err());
+        assert!(GantryArc::new(0.0, 90.0, -10.0, 0.0).is_err());
+        assert!(GantryArc::new(0.0, 90.0, f32::NAN, 0.0).is_err());
+        assert!(GantryArc::new(30.0, 390.0, 10.0, 0.0).is_err());
+
+        let arc = GantryArc::new(0.0, 90.0, 10.0, 0.0).unwrap();
+        let json = serde_json::to_string(&arc).unwrap();
+        assert!(serde_json::from_str::<GantryArc>(&json).is_ok());
+        let zero_spacing = json.replace("\"spacing\":10.0", "\"spacing\":0.0");
+        assert!(serde_json::from_str::<GantryArc>(&zero_spacing).is_err());
+        let zero_sweep = json.replace("\"stop_angle\":90.0", "\"stop_angle\":0.0");
+        assert!(serde_json::from_str::<GantryArc>(&zero_sweep).is_err());
+    }
+}
@@ -1,27 +1,119 @@
-use crate::mask::Mask;
+use crate::arc::GantryArc;
+use crate::collision::{CollisionModel, sample_feasible};
+use crate::labels::{LabelVolume, OUTSIDE_BODY};
+use crate::mask::{Mask, Structure, VoxelMask};
+use crate::nifti::invalid_data;
 use crate::vector::Vector;
 use log::debug;
 use rand::Rng;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::io;
 use std::sync::{Arc, RwLock};
 use std::thread;
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 
-#[derive(Debug, Clone)]
+const GRID_TOLERANCE: f32 = 1e-3;
+
+// Dose grid with sizes in voxels and voxel spacing in mm per axis.
+// Beam geometry and structures are in mm measured from the grid
+// corner, `origin` places that corner in scanner coordinates.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PatientBox {
     pub x_size: i64,
     pub y_size: i64,
     pub z_size: i64,
+    pub spacing: Vector,
+    pub origin: Vector,
 }
 
 impl PatientBox {
+    pub const fn new(x_size: i64, y_size: i64, z_size: i64) -> PatientBox {
+        PatientBox {
+            x_size,
+            y_size,
+            z_size,
+            spacing: Vector::new(1.0, 1.0, 1.0),
+            origin: Vector::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    pub const fn with_spacing(mut self, spacing: Vector) -> PatientBox {
+        self.spacing = spacing;
+        self
+    }
+
+    pub const fn with_origin(mut self, origin: Vector) -> PatientBox {
+        self.origin = origin;
+        self
+    }
+
+    // Grids read from files need at least one voxel per axis, a voxel
+    // count that fits in memory indices and a finite, positive spacing
+    pub fn validate(&self) -> io::Result<()> {
+        let sizes = [self.x_size, self.y_size, self.z_size];
+        if sizes.iter().any(|size| *size < 1) {
+            return Err(invalid_data(format!(
+                "grid sizes must be at least 1, got {:?}",
+                sizes
+            )));
+        }
+        let grid_size = sizes.iter().try_fold(1i64, |n, size| n.checked_mul(*size));
+        if grid_size.is_none_or(|n| usize::try_from(n).is_err()) {
+            return Err(invalid_data("grid has too many voxels"));
+        }
+        let spacing = [self.spacing.x, self.spacing.y, self.spacing.z];
+        if spacing.iter().any(|s| !s.is_finite() || *s <= 0.0) {
+            return Err(invalid_data(format!(
+                "grid spacing must be finite and positive, got {:?}",
+                spacing
+            )));
+        }
+        let origin = [self.origin.x, self.origin.y, self.origin.z];
+        if origin.iter().any(|o| !o.is_finite()) {
+            return Err(invalid_data("grid origin must be finite"));
+        }
+        Ok(())
+    }
+
     pub const fn grid_size(&self) -> i64 {
         self.x_size * self.y_size * self.z_size
     }
+
+    // Physical size of the box in mm
+    pub fn extent(&self) -> Vector {
+        Vector::new(
+            self.x_size as f32 * self.spacing.x,
+            self.y_size as f32 * self.spacing.y,
+            self.z_size as f32 * self.spacing.z,
+        )
+    }
+
+    pub fn voxel_position(&self, x: i64, y: i64, z: i64) -> Vector {
+        Vector::new(
+            x as f32 * self.spacing.x,
+            y as f32 * self.spacing.y,
+            z as f32 * self.spacing.z,
+        )
+    }
+
+    pub fn voxel_volume(&self) -> f32 {
+        self.spacing.x * self.spacing.y * self.spacing.z
+    }
+
+    // Same voxel count, spacing and origin, the latter two within a
+    // small share of a voxel diagonal to allow for rounding in headers
+    pub fn same_grid(&self, other: &PatientBox) -> bool {
+        let tolerance = GRID_TOLERANCE * self.spacing.dist_to_beam();
+        (self.x_size, self.y_size, self.z_size) == (other.x_size, other.y_size, other.z_size)
+            && self.spacing.dist_to_vector(&other.spacing) <= tolerance
+            && self.origin.dist_to_vector(&other.origin) <= tolerance
+    }
 }
 
-#[derive(Debug, Clone)]
+// Axis-aligned box with centre and widths in mm
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TissueBox {
     pub x: i64,
     pub y: i64,
@@ -32,14 +124,32 @@ pub struct TissueBox {
     pub tissue_type: Option<TissueType>,
 }
 
-#[derive(Debug, Clone)]
+impl TissueBox {
+    pub fn centre(&self) -> Vector {
+        Vector::new(self.x as f32, self.y as f32, self.z as f32)
+    }
+}
+
+// A tumour volume with its own prescription, beams aim at the centre
+// of one of the plan's targets
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Target {
+    pub tissue: TissueBox,
+    pub prescription: f32,
+}
+
+pub fn random_target(targets: &[Target]) -> usize {
+    crate::random::rng().random_range(0..targets.len())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum TissueType {
     Tumour,
     SerialOrgan,
     ParallelOrgan,
 }
 
-#[derive(Debug, EnumIter)]
+#[derive(Debug, Clone, Copy, PartialEq, EnumIter)]
 pub enum PatientBoxSide {
     LeftFace,
     RightFace,
@@ -50,50 +160,215 @@ pub enum PatientBoxSide {
 }
 
 pub fn compute_beam_entry(face: &PatientBoxSide, patient_box: &PatientBox) -> Vector {
-    let mut rng = rand::rng();
-    let entry_point = match face {
+    let mut rng = crate::random::rng();
+    let extent = patient_box.extent();
+    match face {
         PatientBoxSide::LeftFace => Vector::new(
             0.0,
-            rng.random_range(0.0..(patient_box.y_size as f32)),
-            rng.random_range(0.0..(patient_box.z_size as f32)),
+            rng.random_range(0.0..extent.y),
+            rng.random_range(0.0..extent.z),
         ),
         PatientBoxSide::RightFace => Vector::new(
-            patient_box.x_size as f32,
-            rng.random_range(0.0..(patient_box.y_size as f32)),
-            rng.random_range(0.0..(patient_box.z_size as f32)),
+            extent.x,
+            rng.random_range(0.0..extent.y),
+            rng.random_range(0.0..extent.z),
         ),
         PatientBoxSide::FrontFace => Vector::new(
-            rng.random_range(0.0..(patient_box.x_size as f32)),
+            rng.random_range(0.0..extent.x),
             0.0,
-            rng.random_range(0.0..(patient_box.z_size as f32)),
+            rng.random_range(0.0..extent.z),
         ),
         PatientBoxSide::BackFace => Vector::new(
-            rng.random_range(0.0..(patient_box.x_size as f32)),
-            patient_box.y_size as f32,
-            rng.random_range(0.0..(patient_box.z_size as f32)),
+            rng.random_range(0.0..extent.x),
+            extent.y,
+            rng.random_range(0.0..extent.z),
         ),
         PatientBoxSide::BottomFace => Vector::new(
-            rng.random_range(0.0..(patient_box.x_size as f32)),
-            rng.random_range(0.0..(patient_box.y_size as f32)),
+            rng.random_range(0.0..extent.x),
+            rng.random_range(0.0..extent.y),
             0.0,
         ),
         PatientBoxSide::TopFace => Vector::new(
-            rng.random_range(0.0..(patient_box.x_size as f32)),
-            rng.random_range(0.0..(patient_box.y_size as f32)),
-            patient_box.z_size as f32,
+            rng.random_range(0.0..extent.x),
+            rng.random_range(0.0..extent.y),
+            extent.z,
         ),
+    }
+}
+
+// Face of the patient box closest to `entry`, entry points drawn by
+// compute_beam_entry lie on it
+pub fn entry_face(entry: &Vector, patient_box: &PatientBox) -> PatientBoxSide {
+    let extent = patient_box.extent();
+    let distance = |face: &PatientBoxSide| match face {
+        PatientBoxSide::LeftFace => entry.x.abs(),
+        PatientBoxSide::RightFace => (extent.x - entry.x).abs(),
+        PatientBoxSide::FrontFace => entry.y.abs(),
+        PatientBoxSide::BackFace => (extent.y - entry.y).abs(),
+        PatientBoxSide::BottomFace => entry.z.abs(),
+        PatientBoxSide::TopFace => (extent.z - entry.z).abs(),
+    };
+    PatientBoxSide::iter()
+        .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+        .unwrap()
+}
+
+// Point where a ray leaving the isocentre along `direction` exits the patient box
+pub fn compute_surface_entry(iso: &Vector, direction: &Vector, patient_box: &PatientBox) -> Vector {
+    let extent = patient_box.extent();
+    let axis_dist = |pos: f32, dir: f32, size: f32| -> f32 {
+        if dir > 0.0 {
+            (size - pos) / dir
+        } else if dir < 0.0 {
+            -pos / dir
+        } else {
+            f32::INFINITY
+        }
     };
-    entry_point
+    let t = axis_dist(iso.x, direction.x, extent.x)
+        .min(axis_dist(iso.y, direction.y, extent.y))
+        .min(axis_dist(iso.z, direction.z, extent.z));
+    Vector::new(
+        iso.x + direction.x * t,
+        iso.y + direction.y * t,
+        iso.z + direction.z * t,
+    )
 }
 
-pub fn generate_beam_entries(patient_box: &PatientBox) -> Vec<Vector> {
-    let mut beams: Vec<Vector> = vec![];
+pub fn compute_random_beam_entry(
+    patient_box: &PatientBox,
+    targets: &[Target],
+    collision: &CollisionModel,
+) -> Option<Beam> {
+    let faces: Vec<PatientBoxSide> = PatientBoxSide::iter().collect();
+    let target = random_target(targets);
+    sample_feasible(
+        || {
+            let face = &faces[crate::random::rng().random_range(0..faces.len())];
+            compute_beam_entry(face, patient_box)
+        },
+        |entry| collision.is_feasible_entry(entry, &targets[target].tissue),
+    )
+    .map(|entry| Beam::Static { entry, target })
+}
+
+// One beam per face aimed at a random target, faces with no reachable
+// entry point are skipped
+pub fn generate_beam_entries(
+    patient_box: &PatientBox,
+    targets: &[Target],
+    collision: &CollisionModel,
+) -> Vec<Beam> {
+    let mut beams: Vec<Beam> = vec![];
     for face in PatientBoxSide::iter() {
-        beams.push(compute_beam_entry(&face, patient_box));
+        let target = random_target(targets);
+        if let Some(entry) = sample_feasible(
+            || compute_beam_entry(&face, patient_box),
+            |entry| collision.is_feasible_entry(entry, &targets[target].tissue),
+        ) {
+            beams.push(Beam::Static { entry, target });
+        }
     }
     beams
 }
 
+// A single beam position with its relative weight, static beams
+// are one control point and arcs are many
+#[derive(Debug, Clone)]
+pub struct ControlPoint {
+    pub entry: Vector,
+    pub aim: Vector,
+    pub weight: f32,
+}
+
+// Every beam aims at the target with index `target` in the plan's target list
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Beam {
+    Static { entry: Vector, target: usize },
+    Arc { arc: GantryArc, target: usize },
+}
+
+const RETARGET_PROB: f32 = 0.1;
+
+impl Beam {
+    pub fn target(&self) -> usize {
+        match self {
+            Beam::Static { target, .. } | Beam::Arc { target, .. } => *target,
+        }
+    }
+
+    pub fn control_points(
+        &self,
+        targets: &[Target],
+        patient_box: &PatientBox,
+    ) -> Vec<ControlPoint> {
+        let tumour = &targets[self.target()].tissue;
+        match self {
+            Beam::Static { entry, .. } => vec![ControlPoint {
+                entry: *entry,
+                aim: tumour.centre(),
+                weight: 1.0,
+            }],
+            Beam::Arc { arc, .. } => arc.control_points(tumour, patient_box),
+        }
+    }
+
+    // Beams of different kinds can't be blended so the first parent's beam
+    // is kept, the child always aims at the first parent's target
+    pub fn crossover(&self, p2: &Beam, alpha: f32) -> Beam {
+        match (self, p2) {
+            (Beam::Static { entry: v1, target }, Beam::Static { entry: v2, .. }) => Beam::Static {
+                entry: v1.crossover(v2, alpha),
+                target: *target,
+            },
+            (Beam::Arc { arc: a1, target }, Beam::Arc { arc: a2, .. }) => Beam::Arc {
+                arc: a1.crossover(a2, alpha),
+                target: *target,
+            },
+            _ => self.clone(),
+        }
+    }
+
+    // Mutations that move the beam into a forbidden direction are undone
+    pub fn mutate(
+        &mut self,
+        mutation_bound: f32,
+        patient: &PatientBox,
+        targets: &[Target],
+        collision: &CollisionModel,
+    ) {
+        let original = self.clone();
+        let retarget =
+            targets.len() > 1 && crate::random::rng().random_range(0.0..1.0) <= RETARGET_PROB;
+        match self {
+            Beam::Static { entry, target } => {
+                entry.mutate(mutation_bound, patient);
+                if retarget {
+                    *target = random_target(targets);
+                }
+            }
+            Beam::Arc { arc, target } => {
+                arc.mutate(mutation_bound);
+                if retarget {
+                    *target = random_target(targets);
+                }
+            }
+        }
+        if !self.is_feasible(targets, collision) {
+            *self = original;
+        }
+    }
+
+    pub fn is_feasible(&self, targets: &[Target], collision: &CollisionModel) -> bool {
+        match self {
+            Beam::Static { entry, target } => {
+                collision.is_feasible_entry(entry, &targets[*target].tissue)
+            }
+            Beam::Arc { arc, .. } => collision.is_feasible_arc(arc),
+        }
+    }
+}
+
 pub struct ComputeDoseParams<const N: usize> {
     pub patient_box: PatientBox,
     pub beams: Vec<Vector>,
@@ -101,13 +376,19 @@ pub struct ComputeDoseParams<const N: usize> {
     pub dose_matrix: Arc<RwLock<Box<[f32; N]>>>,
 }
 
-pub struct ComputeDoseParamsIter<const N: usize> {
+// With a body outline, voxels outside the body get no dose and
+// attenuation starts where each beam crosses the body surface. With
+// a per-voxel attenuation map (from a calibrated CT) beams are
+// attenuated along their radiological depth instead of by MU.
+pub struct ComputeDoseParamsIter {
     pub patient_box: PatientBox,
-    pub beams: Vec<Vector>,
-    pub tumour: TissueBox,
-    pub dose_matrix: Box<[f32; N]>,
+    pub beams: Vec<ControlPoint>,
+    pub dose_matrix: Vec<f32>,
+    pub body: Option<Arc<VoxelMask>>,
+    pub attenuation: Option<Arc<Vec<f32>>>,
 }
 
+// Beam radius in mm and linear attenuation coefficient per mm of water
 const BEAM_RADIUS: f32 = 1.5;
 const E_DEPOSITED: f32 = 0.50;
 const MU: f32 = 0.03;
@@ -123,6 +404,7 @@ pub fn compute_dose<const N: usize>(params: &mut ComputeDoseParams<{ N }>) {
         let local_xmax = params.patient_box.x_size;
         for x in 0..local_xmax {
             let dose_matrix_clone = Arc::clone(&params.dose_matrix);
+            let patient_box = params.patient_box.clone();
             handle_vec.push(thread::spawn(move || {
                 let mut local_dose: Vec<f32> = vec![];
                 for y in 0..local_ymax {
@@ -136,8 +418,7 @@ pub fn compute_dose<const N: usize>(params: &mut ComputeDoseParams<{ N }>) {
                             local_ymax as usize,
                         );
                         let coords = to_coords(test_idx as i64, local_xmax, local_ymax);
-                        let mut vector =
-                            Vector::new(coords.0 as f32, coords.1 as f32, coords.2 as f32);
+                        let mut vector = patient_box.voxel_position(coords.0, coords.1, coords.2);
                         vector.calculate_offset(&beam_entry);
                         let dist = vector.dist_to_beam();
                         let dot_prod = vector.dot(&tumour_vector);
@@ -173,42 +454,144 @@ pub fn compute_dose<const N: usize>(params: &mut ComputeDoseParams<{ N }>) {
     }
 }
 
-fn to_coords(index: i64, x_max: i64, y_max: i64) -> (i64, i64, i64) {
+pub(crate) fn to_coords(index: i64, x_max: i64, y_max: i64) -> (i64, i64, i64) {
     let z = index / (x_max * y_max);
     let y = (index % (x_max * y_max)) / x_max;
     let x = index % x_max;
     (x, y, z)
 }
 
-fn to_index(x: usize, y: usize, z: usize, x_max: usize, y_max: usize) -> usize {
+pub(crate) fn to_index(x: usize, y: usize, z: usize, x_max: usize, y_max: usize) -> usize {
     x + y * x_max + z * x_max * y_max
 }
 
-pub fn compute_dose_iter<const N: usize>(params: &mut ComputeDoseParamsIter<{ N }>) {
+// Walks from the beam entry towards the aim point and returns the first
+// position inside the body, or the aim point if the body is never hit
+pub fn compute_body_entry(
+    entry: &Vector,
+    aim: &Vector,
+    body: &VoxelMask,
+    patient_box: &PatientBox,
+) -> Vector {
+    let mut direction = *aim;
+    direction.calculate_offset(entry);
+    let length = direction.dist_to_beam();
+    let spacing = &patient_box.spacing;
+    let step = spacing.x.min(spacing.y).min(spacing.z) / 2.0;
+    let n_steps = (length / step).ceil() as usize;
+    for i in 0..=n_steps {
+        let t = (i as f32 * step / length).min(1.0);
+        let point = Vector::new(
+            entry.x + direction.x * t,
+            entry.y + direction.y * t,
+            entry.z + direction.z * t,
+        );
+        let (x, y, z) = (
+            (point.x / spacing.x).round() as i64,
+            (point.y / spacing.y).round() as i64,
+            (point.z / spacing.z).round() as i64,
+        );
+        if body.contains(x, y, z) {
+            return point;
+        }
+    }
+    *aim
+}
+
+// Line integral of the attenuation map from `from` to `to`, sampled
+// at the nearest voxel every half voxel. Outside the grid counts as air.
+pub fn compute_radiological_path(
+    from: &Vector,
+    to: &Vector,
+    attenuation: &[f32],
+    patient_box: &PatientBox,
+) -> f32 {
+    let mut direction = *to;
+    direction.calculate_offset(from);
+    let length = direction.dist_to_beam();
+    let spacing = &patient_box.spacing;
+    let n_steps = (length / (spacing.x.min(spacing.y).min(spacing.z) / 2.0)).ceil() as usize;
+    if n_steps == 0 {
+        return 0.0;
+    }
+    let step = length / n_steps as f32;
+    let mut path = 0.0;
+    for i in 0..n_steps {
+        // Midpoint of each step
+        let t = (i as f32 + 0.5) / n_steps as f32;
+        let (x, y, z) = (
+            ((from.x + direction.x * t) / spacing.x).round() as i64,
+            ((from.y + direction.y * t) / spacing.y).round() as i64,
+            ((from.z + direction.z * t) / spacing.z).round() as i64,
+        );
+        if x < 0
+            || y < 0
+            || z < 0
+            || x >= patient_box.x_size
+            || y >= patient_box.y_size
+            || z >= patient_box.z_size
+        {
+            continue;
+        }
+        let idx = to_index(
+            x as usize,
+            y as usize,
+            z as usize,
+            patient_box.x_size as usize,
+            patient_box.y_size as usize,
+        );
+        path += attenuation[idx] * step;
+    }
+    path
+}
+
+pub fn compute_dose_iter(params: &mut ComputeDoseParamsIter) {
     let beams_vec = params.beams.clone();
-    for beam_entry in beams_vec {
-        let tumour_vector = beam_entry.beam_direction(&params.tumour.clone());
+    for control_point in beams_vec {
+        let beam_entry = match &params.body {
+            Some(body) => compute_body_entry(
+                &control_point.entry,
+                &control_point.aim,
+                body,
+                &params.patient_box,
+            ),
+            None => control_point.entry,
+        };
+        let mut tumour_vector = control_point.aim;
+        tumour_vector.calculate_offset(&beam_entry);
         let self_dot = tumour_vector.dot(&tumour_vector);
         let local_ymax = params.patient_box.y_size;
         let local_xmax = params.patient_box.x_size;
+        let patient_box = &params.patient_box;
+        let body = &params.body;
+        let attenuation = &params.attenuation;
         params
             .dose_matrix
             .par_iter_mut()
             .enumerate()
             .for_each(|(idx, val)| {
+                if body.as_ref().is_some_and(|body| !body.contains_index(idx)) {
+                    return;
+                }
                 let mapped_idx = to_coords(idx.try_into().unwrap(), local_xmax, local_ymax);
-                let mut vector = Vector::new(
-                    mapped_idx.0 as f32,
-                    mapped_idx.1 as f32,
-                    mapped_idx.2 as f32,
-                );
+                let mut vector =
+                    patient_box.voxel_position(mapped_idx.0, mapped_idx.1, mapped_idx.2);
                 vector.calculate_offset(&beam_entry);
                 let dist = vector.dist_to_beam();
                 let dot_prod = vector.dot(&tumour_vector);
                 let projection_point = vector.mult_vec(dot_prod / self_dot);
                 let project_dist = vector.dist_to_vector(&projection_point);
                 if project_dist <= BEAM_RADIUS {
-                    *val += E_DEPOSITED * (dist * -MU).exp();
+                    let depth = match attenuation {
+                        Some(attenuation) => compute_radiological_path(
+                            &beam_entry,
+                            &patient_box.voxel_position(mapped_idx.0, mapped_idx.1, mapped_idx.2),
+                            attenuation,
+                            patient_box,
+                        ),
+                        None => dist * MU,
+                    };
+                    *val += control_point.weight * E_DEPOSITED * (-depth).exp();
                 }
             });
     }
@@ -224,11 +607,92 @@ const D_THRESHOLD_S: f32 = 0.0;
 const D_THRESHOLD_P: f32 = 0.0;
 const D_THRESHOLD_H: f32 = 0.375;
 
+const WEIGHT_BEAM_COUNT: f32 = 1.0;
+
+// Cost weights and dose thresholds used to score a plan, the defaults
+// are the constants above
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Objectives {
+    pub weight_tumour: f32,
+    pub weight_serial: f32,
+    pub weight_parallel: f32,
+    pub weight_healthy: f32,
+    pub weight_beam_count: f32,
+    pub default_prescription: f32,
+    pub threshold_serial: f32,
+    pub threshold_parallel: f32,
+    pub threshold_healthy: f32,
+    // Plan quality index terms from the quality module, off by default
+    #[serde(default)]
+    pub weight_rtog_ci: f32,
+    #[serde(default)]
+    pub weight_paddick_ci: f32,
+    #[serde(default)]
+    pub weight_homogeneity: f32,
+    #[serde(default)]
+    pub weight_gradient: f32,
+}
+
+impl Default for Objectives {
+    fn default() -> Objectives {
+        Objectives {
+            weight_tumour: WEIGHT_TUMOUR,
+            weight_serial: WEIGHT_SERIAL,
+            weight_parallel: WEIGHT_PARALLEL,
+            weight_healthy: WEIGHT_HEALTHY,
+            weight_beam_count: WEIGHT_BEAM_COUNT,
+            default_prescription: D_PERSCRIBED,
+            threshold_serial: D_THRESHOLD_S,
+            threshold_parallel: D_THRESHOLD_P,
+            threshold_healthy: D_THRESHOLD_H,
+            weight_rtog_ci: 0.0,
+            weight_paddick_ci: 0.0,
+            weight_homogeneity: 0.0,
+            weight_gradient: 0.0,
+        }
+    }
+}
+
+impl Objectives {
+    pub fn uses_indices(&self) -> bool {
+        self.weight_rtog_ci != 0.0
+            || self.weight_paddick_ci != 0.0
+            || self.weight_homogeneity != 0.0
+            || self.weight_gradient != 0.0
+    }
+}
+
+// Penalises plans with many beams so the optimiser can trade
+// treatment complexity against dose quality
+pub fn compute_beam_count_cost(n_beams: usize, objectives: &Objectives) -> f32 {
+    objectives.weight_beam_count * n_beams as f32
+}
+
+// Each target is scored against its own prescription, targets
+// without one fall back to the default prescription
+fn compute_target_cost<'a>(
+    structures: impl Iterator<Item = (f32, &'a TissueType, Option<f32>)>,
+    default_prescription: f32,
+) -> f32 {
+    let mut tumour_cost: f32 = 0.0;
+    for (dose, t_type, prescription) in structures {
+        if !matches!(t_type, TissueType::Tumour) {
+            continue;
+        }
+        if dose > 0.0 {
+            tumour_cost += (dose - prescription.unwrap_or(default_prescription)).abs();
+        } else {
+            tumour_cost += 1e6;
+        }
+    }
+    tumour_cost
+}
+
 pub fn compute_cost<const N: usize>(
     dose_params: &mut ComputeDoseParams<{ N }>,
-    masks: &Vec<Mask>,
+    masks: &[Mask],
 ) -> f32 {
-    let mut tumour_cost: f32 = 0.0;
+    let mut target_doses: Vec<f32> = vec![0.0; masks.len()];
     let mut serial_oar_cost: f32 = 0.0;
     let mut parallel_oar_cost: f32 = 0.0;
     let mut parallel_oar_intersections: i64 = 0;
@@ -246,7 +710,7 @@ pub fn compute_cost<const N: usize>(
                     dose_params.patient_box.y_size as usize,
                 );
                 let mut mask_hit: bool = false;
-                for mask in masks {
+                for (mask_idx, mask) in masks.iter().enumerate() {
                     // Fix issue with multiple organs and the calculations
                     // being for per organ
                     if mask.bound_check(x, y, z) {
@@ -254,7 +718,7 @@ pub fn compute_cost<const N: usize>(
                             TissueType::Tumour => {
                                 //println!("Hit Tumour");
                                 mask_hit = true;
-                                tumour_cost += dose_maxtrix_read[index];
+                                target_doses[mask_idx] += dose_maxtrix_read[index];
                             }
                             TissueType::SerialOrgan => {
                                 mask_hit = true;
@@ -277,11 +741,13 @@ pub fn compute_cost<const N: usize>(
         }
     }
 
-    if tumour_cost > 0.0 {
-        tumour_cost = (tumour_cost - D_PERSCRIBED).abs();
-    } else {
-        tumour_cost = 1e6;
-    }
+    let tumour_cost = compute_target_cost(
+        target_doses
+            .iter()
+            .zip(masks)
+            .map(|(dose, mask)| (*dose, mask.tissue_type(), mask.prescription())),
+        D_PERSCRIBED,
+    );
     serial_oar_cost = (serial_oar_cost - D_THRESHOLD_S).max(0.0);
 
     let mean_dose: f32 = parallel_oar_cost / parallel_oar_intersections as f32;
@@ -305,11 +771,12 @@ pub fn compute_cost<const N: usize>(
     total_cost
 }
 
-pub fn compute_cost_iter<const N: usize>(
-    dose_params: &mut ComputeDoseParamsIter<{ N }>,
-    masks: &Vec<Mask>,
+pub fn compute_cost_iter<S: Structure>(
+    dose_params: &mut ComputeDoseParamsIter,
+    masks: &[S],
+    objectives: &Objectives,
 ) -> f32 {
-    let mut tumour_cost: f32 = 0.0;
+    let mut target_doses: Vec<f32> = vec![0.0; masks.len()];
     let mut serial_oar_cost: f32 = 0.0;
     let mut parallel_oar_cost: f32 = 0.0;
     let mut parallel_oar_intersections: i64 = 0;
@@ -326,20 +793,21 @@ pub fn compute_cost_iter<const N: usize>(
                     dose_params.patient_box.y_size as usize,
                 );
                 let mut mask_hit: bool = false;
-                for mask in masks {
+                for (mask_idx, mask) in masks.iter().enumerate() {
                     // Fix issue with multiple organs and the calculations
                     // being for per organ
-                    if mask.bound_check(x, y, z) {
-                        match mask.t_type {
+                    if mask.contains(x, y, z) {
+                        match mask.tissue_type() {
                             TissueType::Tumour => {
                                 //println!("Hit Tumour");
                                 mask_hit = true;
-                                tumour_cost += dose_params.dose_matrix[index];
+                                target_doses[mask_idx] += dose_params.dose_matrix[index];
                             }
                             TissueType::SerialOrgan => {
                                 mask_hit = true;
-                                serial_oar_cost +=
-                                    (dose_params.dose_matrix[index] - D_THRESHOLD_S).max(0.0);
+                                serial_oar_cost += (dose_params.dose_matrix[index]
+                                    - objectives.threshold_serial)
+                                    .max(0.0);
                             }
                             TissueType::ParallelOrgan => {
                                 mask_hit = true;
@@ -352,22 +820,24 @@ pub fn compute_cost_iter<const N: usize>(
 
                 if !mask_hit {
                     healthy_tissue_cost +=
-                        (dose_params.dose_matrix[index] - D_THRESHOLD_H).max(0.0);
+                        (dose_params.dose_matrix[index] - objectives.threshold_healthy).max(0.0);
                 }
             }
         }
     }
 
-    if tumour_cost > 0.0 {
-        tumour_cost = (tumour_cost - D_PERSCRIBED).abs();
-    } else {
-        tumour_cost = 1e6;
-    }
-    serial_oar_cost = (serial_oar_cost - D_THRESHOLD_S).max(0.0);
+    let tumour_cost = compute_target_cost(
+        target_doses
+            .iter()
+            .zip(masks)
+            .map(|(dose, mask)| (*dose, mask.tissue_type(), mask.prescription())),
+        objectives.default_prescription,
+    );
+    serial_oar_cost = (serial_oar_cost - objectives.threshold_serial).max(0.0);
 
     let mean_dose: f32 = parallel_oar_cost / parallel_oar_intersections as f32;
-    if mean_dose > D_THRESHOLD_P {
-        parallel_oar_cost = mean_dose - D_THRESHOLD_P;
+    if mean_dose > objectives.threshold_parallel {
+        parallel_oar_cost = mean_dose - objectives.threshold_parallel;
     } else {
         parallel_oar_cost = 0.0;
     }
@@ -377,27 +847,133 @@ pub fn compute_cost_iter<const N: usize>(
     debug!("Parallel Cost: {}", parallel_oar_cost);
     debug!("Healthy Tissue Cost: {}", healthy_tissue_cost);
 
-    let total_cost: f32 = WEIGHT_TUMOUR * tumour_cost
-        + WEIGHT_SERIAL * serial_oar_cost
-        + WEIGHT_PARALLEL * parallel_oar_cost
-        + WEIGHT_HEALTHY * healthy_tissue_cost;
+    let total_cost: f32 = objectives.weight_tumour * tumour_cost
+        + objectives.weight_serial * serial_oar_cost
+        + objectives.weight_parallel * parallel_oar_cost
+        + objectives.weight_healthy * healthy_tissue_cost;
+
+    debug!("Total Cost: {}", total_cost);
+    total_cost
+}
 
+// Unweighted terms of the plan cost, reports show them next to the
+// weights they are combined with
+#[derive(Debug, Clone, PartialEq)]
+pub struct CostBreakdown {
+    pub tumour: f32,
+    pub serial: f32,
+    pub parallel: f32,
+    pub healthy: f32,
+}
+
+impl CostBreakdown {
+    pub fn total(&self, objectives: &Objectives) -> f32 {
+        objectives.weight_tumour * self.tumour
+            + objectives.weight_serial * self.serial
+            + objectives.weight_parallel * self.parallel
+            + objectives.weight_healthy * self.healthy
+    }
+}
+
+// Same cost as compute_cost_iter but reads structure membership from a
+// precomputed label volume, so each voxel is visited once
+pub fn compute_cost_labels(
+    dose_params: &ComputeDoseParamsIter,
+    labels: &LabelVolume,
+    objectives: &Objectives,
+) -> f32 {
+    let total_cost = compute_cost_breakdown(dose_params, labels, objectives).total(objectives);
     debug!("Total Cost: {}", total_cost);
     total_cost
 }
 
+pub fn compute_cost_breakdown(
+    dose_params: &ComputeDoseParamsIter,
+    labels: &LabelVolume,
+    objectives: &Objectives,
+) -> CostBreakdown {
+    let mut target_doses: Vec<f32> = vec![0.0; labels.tissue_types.len()];
+    let mut serial_oar_cost: f32 = 0.0;
+    let mut parallel_oar_cost: f32 = 0.0;
+    let mut parallel_oar_intersections: i64 = 0;
+    let mut healthy_tissue_cost: f32 = 0.0;
+
+    // Visit voxels in the same order as compute_cost_iter so the float
+    // sums come out identical
+    let (x_max, y_max) = (
+        dose_params.patient_box.x_size as usize,
+        dose_params.patient_box.y_size as usize,
+    );
+    for x in 0..x_max {
+        for y in 0..y_max {
+            for z in 0..dose_params.patient_box.z_size as usize {
+                let index = to_index(x, y, z, x_max, y_max);
+                if labels.labels[index] == OUTSIDE_BODY {
+                    continue;
+                }
+                let dose = dose_params.dose_matrix[index];
+                let members = &labels.combinations[labels.labels[index] as usize];
+                if members.is_empty() {
+                    healthy_tissue_cost += (dose - objectives.threshold_healthy).max(0.0);
+                    continue;
+                }
+                for structure_idx in members {
+                    match labels.tissue_types[*structure_idx] {
+                        TissueType::Tumour => {
+                            target_doses[*structure_idx] += dose;
+                        }
+                        TissueType::SerialOrgan => {
+                            serial_oar_cost += (dose - objectives.threshold_serial).max(0.0);
+                        }
+                        TissueType::ParallelOrgan => {
+                            parallel_oar_cost += dose;
+                            parallel_oar_intersections += 1;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let tumour_cost = compute_target_cost(
+        target_doses
+            .iter()
+            .zip(labels.tissue_types.iter().zip(&labels.prescriptions))
+            .map(|(dose, (t_type, prescription))| (*dose, t_type, *prescription)),
+        objectives.default_prescription,
+    );
+    serial_oar_cost = (serial_oar_cost - objectives.threshold_serial).max(0.0);
+
+    let mean_dose: f32 = parallel_oar_cost / parallel_oar_intersections as f32;
+    if mean_dose > objectives.threshold_parallel {
+        parallel_oar_cost = mean_dose - objectives.threshold_parallel;
+    } else {
+        parallel_oar_cost = 0.0;
+    }
+
+    debug!("Tumour Cost: {}", tumour_cost);
+    debug!("Serial Cost: {}", serial_oar_cost);
+    debug!("Parallel Cost: {}", parallel_oar_cost);
+    debug!("Healthy Tissue Cost: {}", healthy_tissue_cost);
+
+    CostBreakdown {
+        tumour: tumour_cost,
+        serial: serial_oar_cost,
+        parallel: parallel_oar_cost,
+        healthy: healthy_tissue_cost,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::labels::OverlapPriority;
+    use crate::mask::VoxelMask;
     use std::time::Instant;
 
     #[test]
     fn test_cost_function() {
-        const PATIENT: PatientBox = PatientBox {
-            x_size: 100,
-            y_size: 50,
-            z_size: 30,
-        };
+        const PATIENT: PatientBox = PatientBox::new(100, 50, 30);
 
         let tumour = TissueBox {
             x: 40,
@@ -429,10 +1005,11 @@ mod tests {
             tissue_type: Some(TissueType::ParallelOrgan),
         };
 
-        let mut mask_holder: Vec<Mask> = vec![];
-        mask_holder.push(Mask::from_tissue_box(&tumour, &PATIENT));
-        mask_holder.push(Mask::from_tissue_box(&serial_organ, &PATIENT));
-        mask_holder.push(Mask::from_tissue_box(&parallel_organ, &PATIENT));
+        let mask_holder: Vec<Mask> = vec![
+            Mask::from_tissue_box(&tumour, &PATIENT),
+            Mask::from_tissue_box(&serial_organ, &PATIENT),
+            Mask::from_tissue_box(&parallel_organ, &PATIENT),
+        ];
         let mut beams: Vec<Vector> = vec![];
         for face in PatientBoxSide::iter() {
             let entry_point = match face {
@@ -469,23 +1046,189 @@ mod tests {
         );
 
         let now2 = Instant::now();
-        let mut dose_params_iter: ComputeDoseParamsIter<{ N_SIZE }> = ComputeDoseParamsIter {
+        let mut dose_params_iter = ComputeDoseParamsIter {
             patient_box: PATIENT.clone(),
-            beams: beams.clone(),
-            tumour: tumour.clone(),
-            dose_matrix: vec![0f32; N_SIZE].try_into().unwrap(),
+            beams: beams
+                .iter()
+                .map(|entry| ControlPoint {
+                    entry: *entry,
+                    aim: tumour.centre(),
+                    weight: 1.0,
+                })
+                .collect(),
+            dose_matrix: vec![0f32; N_SIZE],
+            body: None,
+            attenuation: None,
         };
 
         compute_dose_iter(&mut dose_params_iter);
-        let fitness = compute_cost_iter(&mut dose_params_iter, &mask_holder);
+        let fitness =
+            compute_cost_iter(&mut dose_params_iter, &mask_holder, &Objectives::default());
         println!("Fitness Preset: {}", fitness);
         assert_eq!(fitness, 118.73086);
+
+        let voxel_masks: Vec<Box<dyn Structure>> = mask_holder
+            .iter()
+            .map(|mask| Box::new(VoxelMask::from_mask(mask, &PATIENT)) as Box<dyn Structure>)
+            .collect();
+        let fitness =
+            compute_cost_iter(&mut dose_params_iter, &voxel_masks, &Objectives::default());
+        assert_eq!(fitness, 118.73086);
+
+        let labels =
+            LabelVolume::from_structures(&mask_holder, &PATIENT, &OverlapPriority::CountAll)
+                .unwrap();
+        let fitness = compute_cost_labels(&dose_params_iter, &labels, &Objectives::default());
+        assert_eq!(fitness, 118.73086);
         println!(
             "Time Taken Compute cost and total for iter version: {} Miliseconds",
             now2.elapsed().as_millis()
         );
     }
 
+    #[test]
+    fn test_multiple_target_cost() {
+        const PATIENT: PatientBox = PatientBox::new(10, 10, 10);
+        let primary = Target {
+            tissue: TissueBox {
+                x: 3,
+                y: 3,
+                z: 3,
+                x_width: 3,
+                y_width: 3,
+                z_width: 3,
+                tissue_type: Some(TissueType::Tumour),
+            },
+            prescription: 27.0,
+        };
+        let nodal = Target {
+            tissue: TissueBox {
+                x: 7,
+                y: 7,
+                z: 7,
+                ..primary.tissue.clone()
+            },
+            prescription: 30.0,
+        };
+        let masks = vec![
+            Mask::from_target(&primary, &PATIENT),
+            Mask::from_target(&nodal, &PATIENT),
+        ];
+        const N_SIZE: usize = PATIENT.grid_size() as usize;
+        let mut dose_params = ComputeDoseParamsIter {
+            patient_box: PATIENT.clone(),
+            beams: vec![],
+            dose_matrix: vec![1f32; N_SIZE],
+            body: None,
+            attenuation: None,
+        };
+        // Both targets get 27 voxels of unit dose, only the nodal target
+        // misses its prescription
+        let fitness = compute_cost_iter(&mut dose_params, &masks, &Objectives::default());
+        let healthy = WEIGHT_HEALTHY * (1.0 - D_THRESHOLD_H) * (N_SIZE - 54) as f32;
+        assert_eq!(fitness, 3.0 + healthy);
+    }
+
+    #[test]
+    fn test_anisotropic_dose() {
+        const PATIENT: PatientBox =
+            PatientBox::new(10, 10, 10).with_spacing(Vector::new(2.0, 1.0, 1.0));
+        const N_SIZE: usize = PATIENT.grid_size() as usize;
+        let mut dose_params = ComputeDoseParamsIter {
+            patient_box: PATIENT.clone(),
+            beams: vec![ControlPoint {
+                entry: Vector::new(0.0, 5.0, 5.0),
+                aim: Vector::new(10.0, 5.0, 5.0),
+                weight: 1.0,
+            }],
+            dose_matrix: vec![0f32; N_SIZE],
+            body: None,
+            attenuation: None,
+        };
+        compute_dose_iter(&mut dose_params);
+        // The aim point is voxel 5 along x, 10 mm into the patient
+        let index = to_index(5, 5, 5, 10, 10);
+        assert_eq!(
+            dose_params.dose_matrix[index],
+            E_DEPOSITED * (10.0 * -MU).exp()
+        );
+        assert_eq!(PATIENT.extent().x, 20.0);
+    }
+
+    #[test]
+    fn test_body_outline() {
+        const PATIENT: PatientBox = PatientBox::new(20, 20, 20);
+        const N_SIZE: usize = PATIENT.grid_size() as usize;
+        let body_box = TissueBox {
+            x: 10,
+            y: 10,
+            z: 10,
+            x_width: 10,
+            y_width: 20,
+            z_width: 20,
+            tissue_type: Some(TissueType::ParallelOrgan),
+        };
+        let body = VoxelMask::from_mask(&Mask::from_tissue_box(&body_box, &PATIENT), &PATIENT);
+        let entry = Vector::new(0.0, 10.0, 10.0);
+        let aim = Vector::new(10.0, 10.0, 10.0);
+        let surface = compute_body_entry(&entry, &aim, &body, &PATIENT);
+        // Voxel 5 is the first inside the body and starts half a voxel earlier
+        assert_eq!(surface.x, 4.5);
+
+        let mut dose_params = ComputeDoseParamsIter {
+            patient_box: PATIENT.clone(),
+            beams: vec![ControlPoint {
+                entry,
+                aim,
+                weight: 1.0,
+            }],
+            dose_matrix: vec![0f32; N_SIZE],
+            body: Some(Arc::new(body)),
+            attenuation: None,
+        };
+        compute_dose_iter(&mut dose_params);
+        // Attenuation is counted from the body surface, not the box face
+        assert_eq!(dose_params.dose_matrix[to_index(0, 10, 10, 20, 20)], 0.0);
+        assert_eq!(
+            dose_params.dose_matrix[to_index(10, 10, 10, 20, 20)],
+            E_DEPOSITED * (5.5 * -MU).exp()
+        );
+    }
+
+    #[test]
+    fn test_heterogeneous_attenuation() {
+        const PATIENT: PatientBox = PatientBox::new(20, 20, 20);
+        const N_SIZE: usize = PATIENT.grid_size() as usize;
+        let entry = Vector::new(0.0, 10.0, 10.0);
+        let aim = Vector::new(10.0, 10.0, 10.0);
+        // Water everywhere apart from a 3mm bone slab across the beam
+        let mut attenuation = vec![MU; N_SIZE];
+        for (idx, mu) in attenuation.iter_mut().enumerate() {
+            let (x, _, _) = to_coords(idx as i64, 20, 20);
+            if (3..=5).contains(&x) {
+                *mu = 3.0 * MU;
+            }
+        }
+        assert!(
+            (compute_radiological_path(&entry, &aim, &attenuation, &PATIENT) - 0.48).abs() < 1e-5
+        );
+
+        let mut dose_params = ComputeDoseParamsIter {
+            patient_box: PATIENT.clone(),
+            beams: vec![ControlPoint {
+                entry,
+                aim,
+                weight: 1.0,
+            }],
+            dose_matrix: vec![0f32; N_SIZE],
+            body: None,
+            attenuation: Some(Arc::new(attenuation)),
+        };
+        compute_dose_iter(&mut dose_params);
+        let dose = dose_params.dose_matrix[to_index(10, 10, 10, 20, 20)];
+        assert!((dose - E_DEPOSITED * (-0.48f32).exp()).abs() < 1e-5);
+    }
+
     #[test]
     fn test_coordinate_conversion() {
         let x_max = 5i64;
@@ -0,0 +1,135 @@
+use crate::nifti::invalid_data;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Default CT calibration as (HU, mass density g/cm3, attenuation per mm).
+// Attenuation scales with density so water matches the homogeneous
+// MU used when no CT is available.
+const DEFAULT_TABLE: [(f32, f32, f32); 7] = [
+    (-1000.0, 0.001, 0.00003),
+    (-800.0, 0.2, 0.006),
+    (-100.0, 0.93, 0.0279),
+    (0.0, 1.0, 0.03),
+    (100.0, 1.07, 0.0321),
+    (1000.0, 1.6, 0.048),
+    (3000.0, 2.8, 0.084),
+];
+
+// Piecewise linear curve through (x, y) points sorted by x, values
+// beyond the first and last points are clamped
+#[derive(Debug, Clone)]
+pub struct CalibrationCurve {
+    pub points: Vec<(f32, f32)>,
+}
+
+impl CalibrationCurve {
+    pub fn new(mut points: Vec<(f32, f32)>) -> CalibrationCurve {
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        CalibrationCurve { points }
+    }
+
+    pub fn evaluate(&self, x: f32) -> f32 {
+        let upper = self.points.partition_point(|point| point.0 < x);
+        if upper == 0 {
+            return self.points[0].1;
+        }
+        if upper == self.points.len() {
+            return self.points[upper - 1].1;
+        }
+        let ((x0, y0), (x1, y1)) = (self.points[upper - 1], self.points[upper]);
+        y0 + (y1 - y0) * (x - x0) / (x1 - x0)
+    }
+}
+
+// HU to density and density to linear attenuation, applied in turn to
+// get the per-voxel attenuation used by the dose calculation
+#[derive(Debug, Clone)]
+pub struct Calibration {
+    pub hu_to_density: CalibrationCurve,
+    pub density_to_mu: CalibrationCurve,
+}
+
+impl Default for Calibration {
+    fn default() -> Calibration {
+        Calibration::from_table(&DEFAULT_TABLE)
+    }
+}
+
+impl Calibration {
+    pub fn from_table(table: &[(f32, f32, f32)]) -> Calibration {
+        Calibration {
+            hu_to_density: CalibrationCurve::new(table.iter().map(|row| (row.0, row.1)).collect()),
+            density_to_mu: CalibrationCurve::new(table.iter().map(|row| (row.1, row.2)).collect()),
+        }
+    }
+
+    // Rows of `hu,density,mu`, blank lines, # comments and a header
+    // line that doesn't parse as numbers are skipped
+    pub fn parse_csv(text: &str) -> io::Result<Calibration> {
+        let mut table = Vec::new();
+        for (line_idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let values: Vec<f32> = match line.split(',').map(|v| v.trim().parse()).collect() {
+                Ok(values) => values,
+                Err(_) if table.is_empty() && line_idx == 0 => continue,
+                Err(_) => {
+                    return Err(invalid_data(format!(
+                        "calibration line {} isn't numeric",
+                        line_idx + 1
+                    )));
+                }
+            };
+            if values.len() != 3 {
+                return Err(invalid_data(format!(
+                    "calibration line {} needs hu,density,mu",
+                    line_idx + 1
+                )));
+            }
+            table.push((values[0], values[1], values[2]));
+        }
+        if table.len() < 2 {
+            return Err(invalid_data("calibration needs at least two rows"));
+        }
+        Ok(Calibration::from_table(&table))
+    }
+
+    pub fn load_csv(path: &Path) -> io::Result<Calibration> {
+        Calibration::parse_csv(&fs::read_to_string(path)?)
+    }
+
+    pub fn density(&self, hu: f32) -> f32 {
+        self.hu_to_density.evaluate(hu)
+    }
+
+    pub fn attenuation(&self, hu: f32) -> f32 {
+        self.density_to_mu.evaluate(self.density(hu))
+    }
+
+    // Attenuation per mm for every voxel of a CT in HU
+    pub fn attenuation_map(&self, hounsfield: &[f32]) -> Vec<f32> {
+        hounsfield.iter().map(|hu| self.attenuation(*hu)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calibration_curves() {
+        let default = Calibration::default();
+        assert_eq!(default.attenuation(0.0), 0.03);
+        assert_eq!(default.density(-2000.0), 0.001);
+        assert!((default.density(550.0) - 1.335).abs() < 1e-5);
+
+        let csv = "hu,density,mu\n# soft tissue only\n-1000, 0.0, 0.0\n\n1000, 2.0, 0.06\n";
+        let calibration = Calibration::parse_csv(csv).unwrap();
+        assert_eq!(calibration.density(500.0), 1.5);
+        assert!((calibration.attenuation(0.0) - 0.03).abs() < 1e-6);
+        assert!(Calibration::parse_csv("hu,density,mu\n0,1,0.03\nwater,1,0.03\n").is_err());
+    }
+}
@@ -0,0 +1,145 @@
+use crate::arc::GantryArc;
+use crate::beam_utils::TissueBox;
+use crate::vector::Vector;
+use serde::{Deserialize, Serialize};
+
+// A block of IEC 61217 gantry/couch angles (degrees) the machine can't
+// reach, as written in the RT Plan. Ranges with min > max wrap through 0,
+// so a couch range may be given as 270..90 or -90..90.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForbiddenRegion {
+    pub gantry_min: f32,
+    pub gantry_max: f32,
+    pub couch_min: f32,
+    pub couch_max: f32,
+}
+
+impl ForbiddenRegion {
+    pub fn contains(&self, gantry: f32, couch: f32) -> bool {
+        in_angle_range(gantry, self.gantry_min, self.gantry_max)
+            && in_angle_range(couch, self.couch_min, self.couch_max)
+    }
+}
+
+// Whether `angle` lies clockwise from `min` to `max`, all in degrees
+fn in_angle_range(angle: f32, min: f32, max: f32) -> bool {
+    if max - min >= 360.0 {
+        return true;
+    }
+    (angle - min).rem_euclid(360.0) <= (max - min).rem_euclid(360.0)
+}
+
+const MAX_FEASIBILITY_ATTEMPTS: usize = 100;
+
+// Redraws a random beam until it passes the feasibility check,
+// giving up after MAX_FEASIBILITY_ATTEMPTS draws
+pub fn sample_feasible<T>(
+    mut sample: impl FnMut() -> T,
+    is_feasible: impl Fn(&T) -> bool,
+) -> Option<T> {
+    (0..MAX_FEASIBILITY_ATTEMPTS)
+        .map(|_| sample())
+        .find(|candidate| is_feasible(candidate))
+}
+
+// Feasibility model for beam directions, an empty model allows every direction
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CollisionModel {
+    pub regions: Vec<ForbiddenRegion>,
+}
+
+impl CollisionModel {
+    pub fn new(regions: Vec<ForbiddenRegion>) -> CollisionModel {
+        CollisionModel { regions }
+    }
+
+    pub fn is_feasible_angles(&self, gantry: f32, couch: f32) -> bool {
+        !self
+            .regions
+            .iter()
+            .any(|region| region.contains(gantry, couch))
+    }
+
+    // The beam direction is taken from the tumour (isocentre) back to the
+    // entry point and checked in the same angles the RT Plan exports
+    pub fn is_feasible_entry(&self, entry: &Vector, tumour: &TissueBox) -> bool {
+        let mut direction = *entry;
+        direction.calculate_offset(&tumour.centre());
+        let (gantry, couch) = direction.gantry_couch();
+        self.is_feasible_angles(gantry, couch)
+    }
+
+    pub fn is_feasible_arc(&self, arc: &GantryArc) -> bool {
+        arc.gantry_angles()
+            .iter()
+            .all(|gantry| self.is_feasible_angles(*gantry, arc.couch_angle))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beam_utils::TissueType;
+
+    #[test]
+    fn test_forbidden_regions() {
+        let model = CollisionModel::new(vec![ForbiddenRegion {
+            gantry_min: 330.0,
+            gantry_max: 30.0,
+            couch_min: -90.0,
+            couch_max: 90.0,
+        }]);
+        assert!(!model.is_feasible_angles(0.0, 0.0));
+        assert!(!model.is_feasible_angles(345.0, 10.0));
+        assert!(model.is_feasible_angles(90.0, 0.0));
+
+        let tumour = TissueBox {
+            x: 10,
+            y: 10,
+            z: 10,
+            x_width: 2,
+            y_width: 2,
+            z_width: 2,
+            tissue_type: Some(TissueType::Tumour),
+        };
+        // Straight in from the anterior face is gantry 0
+        assert!(!model.is_feasible_entry(&Vector::new(10.0, 0.0, 10.0), &tumour));
+        assert!(model.is_feasible_entry(&Vector::new(20.0, 10.0, 10.0), &tumour));
+        assert!(!model.is_feasible_arc(&GantryArc::new(300.0, 60.0, 10.0, 0.0).unwrap()));
+        assert!(model.is_feasible_arc(&GantryArc::new(60.0, 300.0, 10.0, 0.0).unwrap()));
+    }
+
+    #[test]
+    fn test_forbidden_region_in_iec_angles() {
+        let tumour = TissueBox {
+            x: 20,
+            y: 20,
+            z: 20,
+            x_width: 2,
+            y_width: 2,
+            z_width: 2,
+            tissue_type: Some(TissueType::Tumour),
+        };
+        // A couch kick the RT Plan writes as 300 is blocked by a region
+        // written in the same machine angles, and by its signed form
+        let entry = |gantry: f32, couch: f32| {
+            let (iso, source) = (tumour.centre(), Vector::from_gantry_couch(gantry, couch));
+            Vector::new(
+                iso.x + 15.0 * source.x,
+                iso.y + 15.0 * source.y,
+                iso.z + 15.0 * source.z,
+            )
+        };
+        for (couch_min, couch_max) in [(290.0, 310.0), (-70.0, -50.0)] {
+            let model = CollisionModel::new(vec![ForbiddenRegion {
+                gantry_min: 50.0,
+                gantry_max: 70.0,
+                couch_min,
+                couch_max,
+            }]);
+            assert!(!model.is_feasible_entry(&entry(60.0, 300.0), &tumour));
+            assert!(model.is_feasible_entry(&entry(60.0, 0.0), &tumour));
+            assert!(model.is_feasible_entry(&entry(120.0, 300.0), &tumour));
+        }
+    }
+}
@@ -0,0 +1,704 @@
+use crate::beam_utils::{PatientBox, TissueType};
+use crate::mask::VoxelMask;
+use crate::nifti::{Anatomy, invalid_data};
+use crate::vector::Vector;
+use log::{debug, warn};
+use rand::Rng;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub type Tag = (u16, u16);
+
+const IMPLICIT_VR_LE: &str = "1.2.840.10008.1.2";
+const EXPLICIT_VR_LE: &str = "1.2.840.10008.1.2.1";
+const IMPLEMENTATION_CLASS_UID: &str = "2.25.158913750232424402861470520183618364281";
+const UNDEFINED_LENGTH: u32 = 0xFFFF_FFFF;
+
+const ITEM: Tag = (0xFFFE, 0xE000);
+const ITEM_DELIMITER: Tag = (0xFFFE, 0xE00D);
+const SEQUENCE_DELIMITER: Tag = (0xFFFE, 0xE0DD);
+
+pub const TRANSFER_SYNTAX: Tag = (0x0002, 0x0010);
+pub const MODALITY: Tag = (0x0008, 0x0060);
+pub const SLICE_THICKNESS: Tag = (0x0018, 0x0050);
+pub const STUDY_INSTANCE_UID: Tag = (0x0020, 0x000D);
+pub const SERIES_INSTANCE_UID: Tag = (0x0020, 0x000E);
+pub const IMAGE_POSITION: Tag = (0x0020, 0x0032);
+pub const IMAGE_ORIENTATION: Tag = (0x0020, 0x0037);
+pub const FRAME_OF_REFERENCE_UID: Tag = (0x0020, 0x0052);
+pub const ROWS: Tag = (0x0028, 0x0010);
+pub const COLUMNS: Tag = (0x0028, 0x0011);
+pub const PIXEL_SPACING: Tag = (0x0028, 0x0030);
+pub const BITS_ALLOCATED: Tag = (0x0028, 0x0100);
+pub const PIXEL_REPRESENTATION: Tag = (0x0028, 0x0103);
+pub const RESCALE_INTERCEPT: Tag = (0x0028, 0x1052);
+pub const RESCALE_SLOPE: Tag = (0x0028, 0x1053);
+pub const STRUCTURE_SET_ROI_SEQUENCE: Tag = (0x3006, 0x0020);
+pub const ROI_NUMBER: Tag = (0x3006, 0x0022);
+pub const ROI_NAME: Tag = (0x3006, 0x0026);
+pub const ROI_CONTOUR_SEQUENCE: Tag = (0x3006, 0x0039);
+pub const CONTOUR_SEQUENCE: Tag = (0x3006, 0x0040);
+pub const CONTOUR_GEOMETRIC_TYPE: Tag = (0x3006, 0x0042);
+pub const CONTOUR_DATA: Tag = (0x3006, 0x0050);
+pub const REFERENCED_ROI_NUMBER: Tag = (0x3006, 0x0084);
+pub const PIXEL_DATA: Tag = (0x7FE0, 0x0010);
+
+// Sequences that have to be recognised in implicit VR files, where
+// nothing but the tag says an element holds items
+const SEQUENCE_TAGS: [Tag; 6] = [
+    (0x3006, 0x0010),
+    (0x3006, 0x0012),
+    (0x3006, 0x0014),
+    STRUCTURE_SET_ROI_SEQUENCE,
+    ROI_CONTOUR_SEQUENCE,
+    CONTOUR_SEQUENCE,
+];
+
+// VRs whose explicit length is 32 bit, preceded by two reserved bytes
+const LONG_VRS: [&[u8; 2]; 13] = [
+    b"OB", b"OD", b"OF", b"OL", b"OV", b"OW", b"SQ", b"SV", b"UC", b"UN", b"UR", b"UT", b"UV",
+];
+
+#[derive(Debug, Clone)]
+pub enum Element {
+    Value(Vec<u8>),
+    Sequence(Vec<DataSet>),
+}
+
+// Parsed DICOM object, only little endian transfer syntaxes are supported
+#[derive(Debug, Clone, Default)]
+pub struct DataSet {
+    pub elements: HashMap<Tag, Element>,
+}
+
+impl DataSet {
+    pub fn bytes(&self, tag: Tag) -> Option<&[u8]> {
+        match self.elements.get(&tag)? {
+            Element::Value(bytes) => Some(bytes),
+            Element::Sequence(_) => None,
+        }
+    }
+
+    pub fn string(&self, tag: Tag) -> Option<String> {
+        let bytes = self.bytes(tag)?;
+        let text = String::from_utf8_lossy(bytes);
+        Some(
+            text.trim_matches(|c: char| c == '\0' || c.is_whitespace())
+                .to_string(),
+        )
+    }
+
+    // Backslash separated decimal strings (DS/IS)
+    pub fn numbers(&self, tag: Tag) -> Vec<f64> {
+        self.string(tag)
+            .map(|text| {
+                text.split('\\')
+                    .filter_map(|value| value.trim().parse().ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn number(&self, tag: Tag) -> Option<f64> {
+        self.numbers(tag).first().copied()
+    }
+
+    pub fn u16(&self, tag: Tag) -> Option<u16> {
+        let bytes = self.bytes(tag)?;
+        Some(u16::from_le_bytes(bytes.get(0..2)?.try_into().unwrap()))
+    }
+
+    pub fn sequence(&self, tag: Tag) -> &[DataSet] {
+        match self.elements.get(&tag) {
+            Some(Element::Sequence(items)) => items,
+            _ => &[],
+        }
+    }
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    explicit: bool,
+}
+
+impl Parser<'_> {
+    fn take(&mut self, n: usize) -> io::Result<&[u8]> {
+        let slice = self
+            .bytes
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| invalid_data("DICOM element runs past the end of the file"))?;
+        self.pos += n;
+        Ok(slice)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn tag(&mut self) -> io::Result<Tag> {
+        Ok((self.u16()?, self.u16()?))
+    }
+
+    // Reads elements until `end` or an item delimiter, `stop` allows
+    // the file meta group to be read on its own
+    fn data_set(&mut self, end: usize, stop: impl Fn(Tag) -> bool) -> io::Result<DataSet> {
+        let mut data_set = DataSet::default();
+        while self.pos < end {
+            let start = self.pos;
+            let tag = self.tag()?;
+            if stop(tag) {
+                self.pos = start;
+                break;
+            }
+            if tag == ITEM_DELIMITER {
+                self.u32()?;
+                break;
+            }
+            let (is_sequence, length) = if self.explicit && tag.0 != 0xFFFE {
+                let vr: [u8; 2] = self.take(2)?.try_into().unwrap();
+                if LONG_VRS.contains(&&vr) {
+                    self.take(2)?;
+                    (&vr == b"SQ", self.u32()?)
+                } else {
+                    (false, self.u16()? as u32)
+                }
+            } else {
+                let length = self.u32()?;
+                let undefined = length == UNDEFINED_LENGTH && tag != PIXEL_DATA;
+                (SEQUENCE_TAGS.contains(&tag) || undefined, length)
+            };
+            let element = if is_sequence {
+                Element::Sequence(self.items(length)?)
+            } else if length == UNDEFINED_LENGTH {
+                return Err(invalid_data(
+                    "encapsulated (compressed) pixel data isn't supported",
+                ));
+            } else {
+                Element::Value(self.take(length as usize)?.to_vec())
+            };
+            data_set.elements.insert(tag, element);
+        }
+        Ok(data_set)
+    }
+
+    fn items(&mut self, length: u32) -> io::Result<Vec<DataSet>> {
+        let end = if length == UNDEFINED_LENGTH {
+            self.bytes.len()
+        } else {
+            self.pos + length as usize
+        };
+        let mut items = Vec::new();
+        while self.pos < end {
+            let tag = self.tag()?;
+            let item_length = self.u32()?;
+            if tag == SEQUENCE_DELIMITER {
+                break;
+            }
+            if tag != ITEM {
+                return Err(invalid_data("expected a DICOM sequence item"));
+            }
+            let item_end = if item_length == UNDEFINED_LENGTH {
+                self.bytes.len()
+            } else {
+                self.pos + item_length as usize
+            };
+            items.push(self.data_set(item_end, |_| false)?);
+        }
+        Ok(items)
+    }
+}
+
+// Parses a Part 10 file, files without the preamble are read as
+// implicit VR little endian
+pub fn parse_dicom(bytes: &[u8]) -> io::Result<DataSet> {
+    if bytes.len() < 132 || &bytes[128..132] != b"DICM" {
+        let mut parser = Parser {
+            bytes,
+            pos: 0,
+            explicit: false,
+        };
+        return parser.data_set(bytes.len(), |_| false);
+    }
+    let mut parser = Parser {
+        bytes,
+        pos: 132,
+        explicit: true,
+    };
+    let mut data_set = parser.data_set(bytes.len(), |tag| tag.0 != 0x0002)?;
+    parser.explicit = match data_set.string(TRANSFER_SYNTAX).as_deref() {
+        Some(IMPLICIT_VR_LE) => false,
+        Some(EXPLICIT_VR_LE) | None => true,
+        Some(other) => {
+            return Err(invalid_data(format!(
+                "unsupported DICOM transfer syntax {other}"
+            )));
+        }
+    };
+    let body = parser.data_set(bytes.len(), |_| false)?;
+    data_set.elements.extend(body.elements);
+    Ok(data_set)
+}
+
+pub fn read_dicom(path: &Path) -> io::Result<DataSet> {
+    parse_dicom(&fs::read(path)?)
+}
+
+// Explicit VR little endian encoder, elements are kept sorted by tag
+// as the standard requires whatever order they are added in
+#[derive(Debug, Clone, Default)]
+pub struct DicomWriter {
+    elements: BTreeMap<Tag, Vec<u8>>,
+}
+
+impl DicomWriter {
+    pub fn element(&mut self, tag: Tag, vr: &[u8; 2], value: &[u8]) -> &mut DicomWriter {
+        let mut value = value.to_vec();
+        if value.len() % 2 == 1 {
+            value.push(if vr == b"UI" || vr == b"OB" { 0 } else { b' ' });
+        }
+        let mut bytes = Vec::with_capacity(value.len() + 12);
+        bytes.extend_from_slice(&tag.0.to_le_bytes());
+        bytes.extend_from_slice(&tag.1.to_le_bytes());
+        bytes.extend_from_slice(vr);
+        if LONG_VRS.contains(&vr) {
+            bytes.extend_from_slice(&[0, 0]);
+            bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        } else {
+            bytes.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        }
+        bytes.extend_from_slice(&value);
+        self.elements.insert(tag, bytes);
+        self
+    }
+
+    pub fn string(&mut self, tag: Tag, vr: &[u8; 2], text: &str) -> &mut DicomWriter {
+        self.element(tag, vr, text.as_bytes())
+    }
+
+    pub fn u16(&mut self, tag: Tag, value: u16) -> &mut DicomWriter {
+        self.element(tag, b"US", &value.to_le_bytes())
+    }
+
+    // Decimal strings are limited to 16 characters
+    pub fn decimals(&mut self, tag: Tag, values: &[f32]) -> &mut DicomWriter {
+        let text: Vec<String> = values.iter().map(|value| decimal_string(*value)).collect();
+        self.string(tag, b"DS", &text.join("\\"))
+    }
+
+    pub fn integer(&mut self, tag: Tag, value: usize) -> &mut DicomWriter {
+        self.string(tag, b"IS", &value.to_string())
+    }
+
+    // Sequence of undefined length holding items of defined length
+    pub fn sequence(&mut self, tag: Tag, items: &[DicomWriter]) -> &mut DicomWriter {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&tag.0.to_le_bytes());
+        bytes.extend_from_slice(&tag.1.to_le_bytes());
+        bytes.extend_from_slice(b"SQ\0\0");
+        bytes.extend_from_slice(&UNDEFINED_LENGTH.to_le_bytes());
+        for item in items {
+            let item = item.to_bytes();
+            bytes.extend_from_slice(&ITEM.0.to_le_bytes());
+            bytes.extend_from_slice(&ITEM.1.to_le_bytes());
+            bytes.extend_from_slice(&(item.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&item);
+        }
+        bytes.extend_from_slice(&SEQUENCE_DELIMITER.0.to_le_bytes());
+        bytes.extend_from_slice(&SEQUENCE_DELIMITER.1.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        self.elements.insert(tag, bytes);
+        self
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.elements.values().flatten().copied().collect()
+    }
+
+    // Preamble, file meta group and the data set, ready to be written
+    pub fn to_part10(&self, sop_class: &str, sop_instance: &str) -> Vec<u8> {
+        let mut meta = DicomWriter::default();
+        meta.element((0x0002, 0x0001), b"OB", &[0, 1])
+            .string((0x0002, 0x0002), b"UI", sop_class)
+            .string((0x0002, 0x0003), b"UI", sop_instance)
+            .string(TRANSFER_SYNTAX, b"UI", EXPLICIT_VR_LE)
+            .string((0x0002, 0x0012), b"UI", IMPLEMENTATION_CLASS_UID);
+        let meta_bytes = meta.to_bytes();
+        let mut group_length = DicomWriter::default();
+        group_length.element(
+            (0x0002, 0x0000),
+            b"UL",
+            &(meta_bytes.len() as u32).to_le_bytes(),
+        );
+
+        let mut bytes = vec![0u8; 128];
+        bytes.extend_from_slice(b"DICM");
+        bytes.extend(group_length.to_bytes());
+        bytes.extend(meta_bytes);
+        bytes.extend(self.to_bytes());
+        bytes
+    }
+}
+
+fn decimal_string(value: f32) -> String {
+    let mut text = format!("{value:.6}");
+    if text.contains('.') {
+        text = text.trim_end_matches('0').trim_end_matches('.').to_string();
+    }
+    if text.len() > 16 {
+        text = format!("{value:.6e}");
+    }
+    text
+}
+
+// UUID derived UID (2.25 root) for new instances, series and studies
+pub fn new_uid() -> String {
+    format!("2.25.{}", rand::rng().random::<u128>())
+}
+
+// Maps an RTSTRUCT ROI name to the structure it describes, names are
+// compared case-insensitively
+#[derive(Debug, Clone)]
+pub struct StructureSpec {
+    pub name: String,
+    pub t_type: TissueType,
+    pub prescription: Option<f32>,
+}
+
+// Largest difference in mm between the gaps of neighbouring CT slices
+const SLICE_GAP_TOLERANCE: f32 = 0.01;
+
+// CT slices sorted along z, values are in HU
+pub fn ct_volume(slices: &[DataSet]) -> io::Result<(PatientBox, Vec<f32>)> {
+    let mut slices: Vec<&DataSet> = slices.iter().collect();
+    let z_position = |slice: &DataSet| slice.numbers(IMAGE_POSITION).get(2).copied();
+    if slices
+        .iter()
+        .any(|slice| !z_position(slice).is_some_and(f64::is_finite))
+    {
+        return Err(invalid_data("CT slice without an image position"));
+    }
+    slices.sort_by(|a, b| z_position(a).unwrap().total_cmp(&z_position(b).unwrap()));
+    let first = *slices
+        .first()
+        .ok_or_else(|| invalid_data("no CT slices found"))?;
+
+    let orientation = first.numbers(IMAGE_ORIENTATION);
+    let axial = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0];
+    if orientation.len() != 6
+        || orientation
+            .iter()
+            .zip(axial)
+            .any(|(a, b)| (a - b).abs() > 1e-3)
+    {
+        return Err(invalid_data(
+            "only axial, head first supine CT is supported",
+        ));
+    }
+    let columns = first
+        .u16(COLUMNS)
+        .ok_or_else(|| invalid_data("CT slice without columns"))? as i64;
+    let rows = first
+        .u16(ROWS)
+        .ok_or_else(|| invalid_data("CT slice without rows"))? as i64;
+    let pixel_spacing = first.numbers(PIXEL_SPACING);
+    if pixel_spacing.len() != 2 {
+        return Err(invalid_data("CT slice without pixel spacing"));
+    }
+    let z_spacing = if slices.len() > 1 {
+        (z_position(slices[1]).unwrap() - z_position(first).unwrap()) as f32
+    } else {
+        first.number(SLICE_THICKNESS).unwrap_or(1.0) as f32
+    };
+    if z_spacing.is_nan() || z_spacing <= 0.0 {
+        return Err(invalid_data("CT slices need distinct z positions"));
+    }
+    // The grid has a single z spacing, so every gap has to match it
+    if slices.windows(2).any(|pair| {
+        let gap = (z_position(pair[1]).unwrap() - z_position(pair[0]).unwrap()) as f32;
+        (gap - z_spacing).abs() > SLICE_GAP_TOLERANCE
+    }) {
+        return Err(invalid_data("CT slices are not evenly spaced along z"));
+    }
+    let position = first.numbers(IMAGE_POSITION);
+    // Pixel spacing is row spacing (y) first, then column spacing (x)
+    let patient_box = PatientBox::new(columns, rows, slices.len() as i64)
+        .with_spacing(Vector::new(
+            pixel_spacing[1] as f32,
+            pixel_spacing[0] as f32,
+            z_spacing,
+        ))
+        .with_origin(Vector::new(
+            position[0] as f32,
+            position[1] as f32,
+            position[2] as f32,
+        ));
+
+    let slice_size = (columns * rows) as usize;
+    let mut hounsfield = Vec::with_capacity(patient_box.grid_size() as usize);
+    for slice in slices {
+        if slice.u16(COLUMNS) != Some(columns as u16) || slice.u16(ROWS) != Some(rows as u16) {
+            return Err(invalid_data("CT slices differ in size"));
+        }
+        if slice.u16(BITS_ALLOCATED) != Some(16) {
+            return Err(invalid_data("only 16 bit CT pixel data is supported"));
+        }
+        let signed = slice.u16(PIXEL_REPRESENTATION) == Some(1);
+        let slope = slice.number(RESCALE_SLOPE).unwrap_or(1.0) as f32;
+        let intercept = slice.number(RESCALE_INTERCEPT).unwrap_or(0.0) as f32;
+        let pixels = slice
+            .bytes(PIXEL_DATA)
+            .filter(|pixels| pixels.len() >= 2 * slice_size)
+            .ok_or_else(|| invalid_data("CT slice pixel data is missing or short"))?;
+        hounsfield.extend(pixels.chunks_exact(2).take(slice_size).map(|pixel| {
+            let raw = if signed {
+                i16::from_le_bytes([pixel[0], pixel[1]]) as f32
+            } else {
+                u16::from_le_bytes([pixel[0], pixel[1]]) as f32
+            };
+            raw * slope + intercept
+        }));
+    }
+    Ok((patient_box, hounsfield))
+}
+
+// Even-odd test of a voxel centre against a contour in grid units
+fn inside_polygon(x: f32, y: f32, polygon: &[(f32, f32)]) -> bool {
+    let mut inside = false;
+    let mut j = polygon.len() - 1;
+    for i in 0..polygon.len() {
+        let (xi, yi) = polygon[i];
+        let (xj, yj) = polygon[j];
+        if (yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+// Rasterises the closed planar contours of the named ROIs onto the
+// grid. Contours on the same slice are combined even-odd so holes
+// drawn as inner contours are kept out of the mask
+pub fn rtstruct_masks(
+    rtstruct: &DataSet,
+    p_box: &PatientBox,
+    table: &[StructureSpec],
+) -> Vec<VoxelMask> {
+    let names: HashMap<String, String> = rtstruct
+        .sequence(STRUCTURE_SET_ROI_SEQUENCE)
+        .iter()
+        .filter_map(|roi| Some((roi.string(ROI_NUMBER)?, roi.string(ROI_NAME)?)))
+        .collect();
+    let (origin, spacing) = (&p_box.origin, &p_box.spacing);
+
+    let mut masks = Vec::new();
+    for roi in rtstruct.sequence(ROI_CONTOUR_SEQUENCE) {
+        let Some(name) = roi
+            .string(REFERENCED_ROI_NUMBER)
+            .and_then(|number| names.get(&number))
+        else {
+            continue;
+        };
+        let Some(spec) = table
+            .iter()
+            .find(|spec| spec.name.eq_ignore_ascii_case(name))
+        else {
+            debug!("Skipping ROI {name}, it isn't in the structure table");
+            continue;
+        };
+        let mut mask = VoxelMask::new(p_box, spec.t_type.clone());
+        mask.prescription = spec.prescription;
+        for contour in roi.sequence(CONTOUR_SEQUENCE) {
+            if contour.string(CONTOUR_GEOMETRIC_TYPE).as_deref() != Some("CLOSED_PLANAR") {
+                continue;
+            }
+            let points = contour.numbers(CONTOUR_DATA);
+            if points.len() < 9 {
+                continue;
+            }
+            let z = ((points[2] as f32 - origin.z) / spacing.z).round() as i64;
+            if z < 0 || z >= p_box.z_size {
+                warn!("Contour of {name} lies outside the CT");
+                continue;
+            }
+            let polygon: Vec<(f32, f32)> = points
+                .chunks_exact(3)
+                .map(|p| {
+                    (
+                        (p[0] as f32 - origin.x) / spacing.x,
+                        (p[1] as f32 - origin.y) / spacing.y,
+                    )
+                })
+                .collect();
+            let (x_min, x_max, y_min, y_max) = polygon.iter().fold(
+                (f32::MAX, f32::MIN, f32::MAX, f32::MIN),
+                |(x0, x1, y0, y1), (x, y)| (x0.min(*x), x1.max(*x), y0.min(*y), y1.max(*y)),
+            );
+            for y in (y_min.ceil().max(0.0) as i64)..=(y_max.floor() as i64).min(p_box.y_size - 1) {
+                for x in
+                    (x_min.ceil().max(0.0) as i64)..=(x_max.floor() as i64).min(p_box.x_size - 1)
+                {
+                    if inside_polygon(x as f32, y as f32, &polygon) {
+                        let toggled = !mask.contains(x, y, z);
+                        mask.set(x, y, z, toggled);
+                    }
+                }
+            }
+        }
+        masks.push(mask);
+    }
+    masks
+}
+
+// Loads every CT slice and the RTSTRUCT found in a directory, files
+// that aren't DICOM are ignored
+pub fn load_dicom(dir: &Path, table: &[StructureSpec]) -> io::Result<Anatomy> {
+    let mut series: HashMap<String, Vec<DataSet>> = HashMap::new();
+    let mut rtstruct = None;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let Ok(data_set) = read_dicom(&path) else {
+            debug!("Skipping {}, not a readable DICOM file", path.display());
+            continue;
+        };
+        match data_set.string(MODALITY).as_deref() {
+            Some("CT") => series
+                .entry(data_set.string(SERIES_INSTANCE_UID).unwrap_or_default())
+                .or_default()
+                .push(data_set),
+            Some("RTSTRUCT") => rtstruct = Some(data_set),
+            _ => {}
+        }
+    }
+    // With several series in the directory the largest one is used
+    let slices = series
+        .into_values()
+        .max_by_key(|slices| slices.len())
+        .ok_or_else(|| invalid_data("no CT series found"))?;
+    let rtstruct = rtstruct.ok_or_else(|| invalid_data("no RTSTRUCT found"))?;
+    let (patient_box, hounsfield) = ct_volume(&slices)?;
+    Ok(Anatomy {
+        masks: rtstruct_masks(&rtstruct, &patient_box, table),
+        patient_box,
+        hounsfield,
+        study_uid: slices[0].string(STUDY_INSTANCE_UID),
+        frame_of_reference_uid: slices[0].string(FRAME_OF_REFERENCE_UID),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ct_slice(z: f32) -> Vec<u8> {
+        let pixels: Vec<u8> = (0..24u16).flat_map(|i| (1024 + i).to_le_bytes()).collect();
+        let mut slice = DicomWriter::default();
+        slice
+            .string(MODALITY, b"CS", "CT")
+            .string(STUDY_INSTANCE_UID, b"UI", "1.2.2")
+            .string(SERIES_INSTANCE_UID, b"UI", "1.2.3")
+            .string(FRAME_OF_REFERENCE_UID, b"UI", "1.2.4")
+            .decimals(IMAGE_POSITION, &[-5.0, -5.0, z])
+            .decimals(IMAGE_ORIENTATION, &[1.0, 0.0, 0.0, 0.0, 1.0, 0.0])
+            .u16(ROWS, 4)
+            .u16(COLUMNS, 6)
+            .decimals(PIXEL_SPACING, &[2.0, 1.0])
+            .u16(BITS_ALLOCATED, 16)
+            .u16(PIXEL_REPRESENTATION, 0)
+            .decimals(RESCALE_INTERCEPT, &[-1024.0])
+            .decimals(RESCALE_SLOPE, &[1.0])
+            .element(PIXEL_DATA, b"OW", &pixels);
+        slice.to_part10("1.2.840.10008.5.1.4.1.1.2", &new_uid())
+    }
+
+    fn rtstruct() -> Vec<u8> {
+        let mut roi = DicomWriter::default();
+        roi.integer(ROI_NUMBER, 7).string(ROI_NAME, b"LO", "PTV");
+        let mut contour = DicomWriter::default();
+        // Square from x = -4..-2 and y = -4..0 mm on the second slice
+        contour
+            .string(CONTOUR_GEOMETRIC_TYPE, b"CS", "CLOSED_PLANAR")
+            .decimals(
+                CONTOUR_DATA,
+                &[
+                    -4.2, -4.2, 3.0, -1.8, -4.2, 3.0, -1.8, 0.2, 3.0, -4.2, 0.2, 3.0,
+                ],
+            );
+        let mut roi_contour = DicomWriter::default();
+        roi_contour
+            .integer(REFERENCED_ROI_NUMBER, 7)
+            .sequence(CONTOUR_SEQUENCE, &[contour]);
+
+        let mut structure_set = DicomWriter::default();
+        structure_set
+            .string(MODALITY, b"CS", "RTSTRUCT")
+            .sequence(STRUCTURE_SET_ROI_SEQUENCE, &[roi])
+            .sequence(ROI_CONTOUR_SEQUENCE, &[roi_contour]);
+        structure_set.to_part10("1.2.840.10008.5.1.4.1.1.481.3", &new_uid())
+    }
+
+    #[test]
+    fn test_load_dicom() {
+        let dir = std::env::temp_dir().join(format!("dicom_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("ct2.dcm"), ct_slice(3.0)).unwrap();
+        fs::write(dir.join("ct1.dcm"), ct_slice(0.0)).unwrap();
+        fs::write(dir.join("rs.dcm"), rtstruct()).unwrap();
+        fs::write(dir.join("notes.txt"), b"not dicom").unwrap();
+        let table = [StructureSpec {
+            name: "ptv".to_string(),
+            t_type: TissueType::Tumour,
+            prescription: Some(40.0),
+        }];
+        let anatomy = load_dicom(&dir, &table);
+        fs::remove_dir_all(&dir).unwrap();
+        let anatomy = anatomy.unwrap();
+
+        let p_box = &anatomy.patient_box;
+        assert_eq!((p_box.x_size, p_box.y_size, p_box.z_size), (6, 4, 2));
+        assert_eq!(
+            (p_box.spacing.x, p_box.spacing.y, p_box.spacing.z),
+            (1.0, 2.0, 3.0)
+        );
+        assert_eq!((p_box.origin.x, p_box.origin.z), (-5.0, 0.0));
+        assert_eq!(anatomy.hounsfield[0], 0.0);
+        assert_eq!(anatomy.hounsfield[24 + 23], 23.0);
+        assert_eq!(anatomy.study_uid.as_deref(), Some("1.2.2"));
+        assert_eq!(anatomy.frame_of_reference_uid.as_deref(), Some("1.2.4"));
+
+        // Voxel centres at x = -4, -3, -2 and y = -3, -1 fall inside
+        let ptv = &anatomy.masks[0];
+        assert_eq!(ptv.count(), 6);
+        assert!(ptv.contains(1, 1, 1) && ptv.contains(3, 2, 1));
+        assert!(!ptv.contains(1, 1, 0));
+    }
+
+    #[test]
+    fn test_ct_slice_spacing() {
+        let slices = |positions: &[f32]| -> Vec<DataSet> {
+            positions
+                .iter()
+                .map(|z| parse_dicom(&ct_slice(*z)).unwrap())
+                .collect()
+        };
+        let (p_box, _) = ct_volume(&slices(&[6.0, 0.0, 3.0])).unwrap();
+        assert_eq!(
+            (p_box.z_size, p_box.spacing.z, p_box.origin.z),
+            (3, 3.0, 0.0)
+        );
+        // Duplicate slices, uneven gaps and unreadable positions
+        assert!(ct_volume(&slices(&[0.0, 0.0, 3.0])).is_err());
+        assert!(ct_volume(&slices(&[0.0, 3.0, 9.0])).is_err());
+        assert!(ct_volume(&slices(&[0.0, f32::NAN])).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use crate::beam_utils::TissueType;
+use crate::mask::VoxelMask;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Cumulative dose volume histogram of one structure, kept as the sorted
+// voxel doses so dose and volume statistics come out exact
+#[derive(Debug, Clone)]
+pub struct Dvh {
+    pub name: String,
+    pub t_type: TissueType,
+    pub prescription: Option<f32>,
+    pub doses: Vec<f32>,
+}
+
+impl Dvh {
+    pub fn from_mask(name: &str, mask: &VoxelMask, dose: &[f32]) -> Dvh {
+        let mut doses: Vec<f32> = (0..dose.len())
+            .filter(|index| mask.contains_index(*index))
+            .map(|index| dose[index])
+            .collect();
+        doses.sort_by(|a, b| a.total_cmp(b));
+        Dvh {
+            name: name.to_string(),
+            t_type: mask.t_type.clone(),
+            prescription: mask.prescription,
+            doses,
+        }
+    }
+
+    pub fn min(&self) -> f32 {
+        self.doses.first().copied().unwrap_or(0.0)
+    }
+
+    pub fn max(&self) -> f32 {
+        self.doses.last().copied().unwrap_or(0.0)
+    }
+
+    pub fn mean(&self) -> f32 {
+        if self.doses.is_empty() {
+            return 0.0;
+        }
+        self.doses.iter().sum::<f32>() / self.doses.len() as f32
+    }
+
+    // Fraction of the structure receiving at least `dose`
+    pub fn volume_at_dose(&self, dose: f32) -> f32 {
+        if self.doses.is_empty() {
+            return 0.0;
+        }
+        let below = self.doses.partition_point(|voxel| *voxel < dose);
+        (self.doses.len() - below) as f32 / self.doses.len() as f32
+    }
+
+    // Minimum dose to the hottest `fraction` of the structure, D95 is
+    // dose_at_volume(0.95)
+    pub fn dose_at_volume(&self, fraction: f32) -> f32 {
+        if self.doses.is_empty() {
+            return 0.0;
+        }
+        let hottest = ((fraction * self.doses.len() as f32).ceil() as usize).max(1);
+        self.doses[self.doses.len() - hottest.min(self.doses.len())]
+    }
+
+    // Cumulative curve as (dose, volume fraction) every `bin_width`
+    // from zero up to the maximum dose
+    pub fn curve(&self, bin_width: f32) -> Vec<(f32, f32)> {
+        let bins = (self.max() / bin_width).ceil() as usize;
+        (0..=bins)
+            .map(|bin| {
+                let dose = bin as f32 * bin_width;
+                (dose, self.volume_at_dose(dose))
+            })
+            .collect()
+    }
+}
+
+// One row per dose bin, one volume column per structure
+pub fn write_dvh_csv(path: &Path, dvhs: &[Dvh], bin_width: f32) -> io::Result<()> {
+    if bin_width.is_nan() || bin_width <= 0.0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "DVH bin width must be positive",
+        ));
+    }
+    let max_dose = dvhs.iter().map(|dvh| dvh.max()).fold(0.0, f32::max);
+    let bins = (max_dose / bin_width).ceil() as usize;
+    let mut text = String::from("dose");
+    for dvh in dvhs {
+        text.push_str(&format!(",{}", dvh.name));
+    }
+    text.push('\n');
+    for bin in 0..=bins {
+        let dose = bin as f32 * bin_width;
+        text.push_str(&format!("{}", dose));
+        for dvh in dvhs {
+            text.push_str(&format!(",{}", dvh.volume_at_dose(dose)));
+        }
+        text.push('\n');
+    }
+    fs::write(path, text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beam_utils::PatientBox;
+
+    #[test]
+    fn test_dvh_statistics() {
+        let patient = PatientBox::new(10, 1, 1);
+        let mut mask = VoxelMask::new(&patient, TissueType::Tumour);
+        for x in 0..10 {
+            mask.set(x, 0, 0, true);
+        }
+        let dose: Vec<f32> = (1..=10).map(|d| d as f32).collect();
+        let dvh = Dvh::from_mask("PTV", &mask, &dose);
+        assert_eq!(dvh.min(), 1.0);
+        assert_eq!(dvh.max(), 10.0);
+        assert_eq!(dvh.mean(), 5.5);
+        assert_eq!(dvh.volume_at_dose(8.0), 0.3);
+        assert_eq!(dvh.dose_at_volume(0.95), 1.0);
+        assert_eq!(dvh.dose_at_volume(0.2), 9.0);
+        let curve = dvh.curve(5.0);
+        assert_eq!(curve, vec![(0.0, 1.0), (5.0, 0.6), (10.0, 0.1)]);
+    }
+}
@@ -0,0 +1,168 @@
+use crate::beam_utils::PatientBox;
+use crate::mask::VoxelMask;
+use crate::nifti::write_nifti;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use strum_macros::EnumString;
+
+// File formats dose grids and masks can be written in, all of them
+// open in 3D Slicer and ParaView
+#[derive(Debug, Clone, Copy, PartialEq, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum ExportFormat {
+    Nifti,
+    Nrrd,
+    Vtk,
+}
+
+impl ExportFormat {
+    // Picks the format from the file extension, .nii.gz counts as NIfTI
+    pub fn from_path(path: &Path) -> Option<ExportFormat> {
+        let name = path.file_name()?.to_str()?.to_lowercase();
+        if name.ends_with(".nii") || name.ends_with(".nii.gz") {
+            Some(ExportFormat::Nifti)
+        } else if name.ends_with(".nrrd") {
+            Some(ExportFormat::Nrrd)
+        } else if name.ends_with(".vtk") {
+            Some(ExportFormat::Vtk)
+        } else {
+            None
+        }
+    }
+}
+
+// Mask as a 0/1 grid so it can go through the same writers as dose
+pub fn mask_grid(mask: &VoxelMask) -> Vec<f32> {
+    (0..mask.patient_box.grid_size() as usize)
+        .map(|index| if mask.contains_index(index) { 1.0 } else { 0.0 })
+        .collect()
+}
+
+// Writes a grid in dose matrix order, `name` ends up as the scalar
+// name or description depending on the format
+pub fn export_grid(
+    path: &Path,
+    format: ExportFormat,
+    p_box: &PatientBox,
+    data: &[f32],
+    name: &str,
+) -> io::Result<()> {
+    if data.len() != p_box.grid_size() as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "grid length doesn't match the patient box",
+        ));
+    }
+    match format {
+        ExportFormat::Nifti => write_nifti(path, p_box, data, name),
+        ExportFormat::Nrrd => fs::write(path, encode_nrrd(p_box, data, name)?),
+        ExportFormat::Vtk => fs::write(path, encode_vtk(p_box, data, name)?),
+    }
+}
+
+// Single-file NRRD with a raw little endian float payload
+pub fn encode_nrrd(p_box: &PatientBox, data: &[f32], name: &str) -> io::Result<Vec<u8>> {
+    let (spacing, origin) = (&p_box.spacing, &p_box.origin);
+    let mut bytes = Vec::new();
+    writeln!(bytes, "NRRD0004")?;
+    writeln!(bytes, "content: {name}")?;
+    writeln!(bytes, "type: float")?;
+    writeln!(bytes, "dimension: 3")?;
+    writeln!(bytes, "space dimension: 3")?;
+    writeln!(
+        bytes,
+        "sizes: {} {} {}",
+        p_box.x_size, p_box.y_size, p_box.z_size
+    )?;
+    writeln!(
+        bytes,
+        "space directions: ({},0,0) (0,{},0) (0,0,{})",
+        spacing.x, spacing.y, spacing.z
+    )?;
+    writeln!(bytes, "space units: \"mm\" \"mm\" \"mm\"")?;
+    writeln!(
+        bytes,
+        "space origin: ({},{},{})",
+        origin.x, origin.y, origin.z
+    )?;
+    writeln!(bytes, "endian: little")?;
+    writeln!(bytes, "encoding: raw")?;
+    writeln!(bytes)?;
+    for value in data {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    Ok(bytes)
+}
+
+// Legacy VTK structured points, binary payloads are big endian
+pub fn encode_vtk(p_box: &PatientBox, data: &[f32], name: &str) -> io::Result<Vec<u8>> {
+    let (spacing, origin) = (&p_box.spacing, &p_box.origin);
+    // Scalar names can't contain whitespace
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_whitespace() { '_' } else { c })
+        .collect();
+    let mut bytes = Vec::new();
+    writeln!(bytes, "# vtk DataFile Version 3.0")?;
+    writeln!(bytes, "{name}")?;
+    writeln!(bytes, "BINARY")?;
+    writeln!(bytes, "DATASET STRUCTURED_POINTS")?;
+    writeln!(
+        bytes,
+        "DIMENSIONS {} {} {}",
+        p_box.x_size, p_box.y_size, p_box.z_size
+    )?;
+    writeln!(bytes, "ORIGIN {} {} {}", origin.x, origin.y, origin.z)?;
+    writeln!(bytes, "SPACING {} {} {}", spacing.x, spacing.y, spacing.z)?;
+    writeln!(bytes, "POINT_DATA {}", data.len())?;
+    writeln!(bytes, "SCALARS {name} float 1")?;
+    writeln!(bytes, "LOOKUP_TABLE default")?;
+    for value in data {
+        bytes.extend_from_slice(&value.to_be_bytes());
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nifti::read_nifti;
+    use crate::vector::Vector;
+
+    #[test]
+    fn test_export_formats() {
+        let p_box = PatientBox::new(3, 2, 2)
+            .with_spacing(Vector::new(2.5, 2.5, 3.0))
+            .with_origin(Vector::new(-100.0, 50.0, 0.0));
+        let dose: Vec<f32> = (0..12).map(|i| i as f32 * 0.5).collect();
+        let dir = std::env::temp_dir();
+        let id = std::process::id();
+
+        let nifti_path = dir.join(format!("dose_{id}.nii.gz"));
+        assert_eq!(
+            ExportFormat::from_path(&nifti_path),
+            Some(ExportFormat::Nifti)
+        );
+        export_grid(&nifti_path, ExportFormat::Nifti, &p_box, &dose, "dose").unwrap();
+        let volume = read_nifti(&nifti_path);
+        fs::remove_file(&nifti_path).unwrap();
+        let volume = volume.unwrap();
+        assert_eq!(volume.data, dose);
+        assert_eq!(volume.patient_box.spacing.z, 3.0);
+        assert_eq!(volume.patient_box.origin.x, -100.0);
+
+        let nrrd = encode_nrrd(&p_box, &dose, "dose").unwrap();
+        let header_end = nrrd.windows(2).position(|w| w == b"\n\n").unwrap() + 2;
+        assert!(nrrd.starts_with(b"NRRD0004\n"));
+        assert_eq!(nrrd.len() - header_end, 12 * 4);
+
+        let vtk = encode_vtk(&p_box, &dose, "plan dose").unwrap();
+        let text = String::from_utf8_lossy(&vtk);
+        assert!(text.contains("DIMENSIONS 3 2 2\n"));
+        assert!(text.contains("SCALARS plan_dose float 1\n"));
+        assert_eq!(&vtk[vtk.len() - 4..], &5.5f32.to_be_bytes());
+
+        assert!(export_grid(&nifti_path, ExportFormat::Vtk, &p_box, &dose[1..], "dose").is_err());
+    }
+}
@@ -1,60 +1,204 @@
+use crate::arc::GantryArc;
 use crate::beam_utils::PatientBox;
-use crate::beam_utils::TissueBox;
+use crate::beam_utils::Target;
 use crate::beam_utils::{
-    ComputeDoseParamsIter, compute_cost_iter, compute_dose_iter, generate_beam_entries,
+    Beam, ComputeDoseParamsIter, Objectives, compute_beam_count_cost, compute_cost_labels,
+    compute_dose_iter, compute_random_beam_entry, entry_face, generate_beam_entries, random_target,
 };
-use crate::mask::Mask;
-use crate::vector::Vector;
-use rayon::prelude::*;
+use crate::collision::{CollisionModel, sample_feasible};
+use crate::labels::LabelVolume;
+use crate::quality::compute_index_cost;
 use log::debug;
 use rand::Rng;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::io;
 
 #[derive(Clone)]
 pub struct Indv {
-    pub beams: Vec<Vector>,
+    pub beams: Vec<Beam>,
     pub fitness: f32,
 }
 
+// Which beam kinds make up the genome, static six-field plans,
+// rotational arc plans or a mix of both
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DeliveryMode {
+    Static,
+    Arc,
+    Mixed,
+}
+
+impl DeliveryMode {
+    pub fn initial_beams(
+        &self,
+        patient_box: &PatientBox,
+        targets: &[Target],
+        collision: &CollisionModel,
+    ) -> Vec<Beam> {
+        let static_beams = || generate_beam_entries(patient_box, targets, collision).into_iter();
+        let random_arc = || {
+            sample_feasible(GantryArc::random, |arc| collision.is_feasible_arc(arc)).map(|arc| {
+                Beam::Arc {
+                    arc,
+                    target: random_target(targets),
+                }
+            })
+        };
+        match self {
+            DeliveryMode::Static => static_beams().collect(),
+            DeliveryMode::Arc => (0..INITIAL_ARCS).filter_map(|_| random_arc()).collect(),
+            DeliveryMode::Mixed => static_beams().chain(random_arc()).collect(),
+        }
+    }
+
+    // None when no feasible beam could be drawn
+    pub fn random_beam(
+        &self,
+        patient_box: &PatientBox,
+        targets: &[Target],
+        collision: &CollisionModel,
+    ) -> Option<Beam> {
+        let use_arc = match self {
+            DeliveryMode::Static => false,
+            DeliveryMode::Arc => true,
+            DeliveryMode::Mixed => crate::random::rng().random_bool(0.5),
+        };
+        if use_arc {
+            sample_feasible(GantryArc::random, |arc| collision.is_feasible_arc(arc)).map(|arc| {
+                Beam::Arc {
+                    arc,
+                    target: random_target(targets),
+                }
+            })
+        } else {
+            compute_random_beam_entry(patient_box, targets, collision)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GaConfig {
+    pub population_size: usize,
+    pub generations: usize,
+    pub tournament_size: usize,
+    pub delivery: DeliveryMode,
+}
+
+impl GaConfig {
+    // The loop needs one generation to return a plan, two parents per
+    // crossover and tournaments that fit in the population
+    pub fn validate(&self) -> io::Result<()> {
+        let problem = if self.generations < 1 {
+            "generations must be at least 1"
+        } else if self.population_size < 2 {
+            "population_size must be at least 2"
+        } else if self.tournament_size < 1 || self.tournament_size > self.population_size {
+            "tournament_size must be between 1 and population_size"
+        } else {
+            return Ok(());
+        };
+        Err(io::Error::new(io::ErrorKind::InvalidInput, problem))
+    }
+}
+
 impl Indv {
-    pub fn calculate_fitness<const N_SIZE: usize>(
+    pub fn calculate_fitness(
         &mut self,
         patient: &PatientBox,
-        tumour: &TissueBox,
-        mask_holder: &Vec<Mask>,
+        targets: &[Target],
+        labels: &LabelVolume,
+        objectives: &Objectives,
     ) {
-        let mut dose_params: ComputeDoseParamsIter<{ N_SIZE }> = ComputeDoseParamsIter {
+        let dose_params = self.compute_dose(patient, targets, labels);
+        self.fitness = compute_cost_labels(&dose_params, labels, objectives)
+            + compute_beam_count_cost(self.beams.len(), objectives)
+            + compute_index_cost(&dose_params.dose_matrix, labels, objectives);
+    }
+
+    // Dose grid of the plan, also used to export the optimised plan
+    pub fn compute_dose(
+        &self,
+        patient: &PatientBox,
+        targets: &[Target],
+        labels: &LabelVolume,
+    ) -> ComputeDoseParamsIter {
+        let mut dose_params = ComputeDoseParamsIter {
             patient_box: patient.clone(),
-            beams: self.beams.clone(),
-            tumour: tumour.clone(),
-            dose_matrix: vec![0f32; N_SIZE].try_into().unwrap(),
+            beams: self
+                .beams
+                .iter()
+                .flat_map(|beam| beam.control_points(targets, patient))
+                .collect(),
+            dose_matrix: vec![0f32; patient.grid_size() as usize],
+            body: labels.body.clone(),
+            attenuation: labels.attenuation.clone(),
         };
         compute_dose_iter(&mut dose_params);
-        self.fitness = compute_cost_iter(&mut dose_params, &mask_holder);
+        dose_params
     }
-    pub fn mutation(&mut self, patient: &PatientBox, mutation_prop: f32, mutation_bound: f32) {
-        let mut rng = rand::rng();
+
+    pub fn mutation(
+        &mut self,
+        patient: &PatientBox,
+        targets: &[Target],
+        collision: &CollisionModel,
+        mutation_prop: f32,
+        mutation_bound: f32,
+    ) {
+        let mut rng = crate::random::rng();
         let draw: f32 = rng.random_range(0.0..1.0);
         if draw <= mutation_prop {
             for beam in &mut self.beams {
-                beam.mutate(mutation_bound, &patient);
+                beam.mutate(mutation_bound, patient, targets, collision);
+            }
+        }
+    }
+
+    // Grows or shrinks the genome by a single beam, keeping the
+    // beam count within MIN_BEAMS..=MAX_BEAMS
+    pub fn beam_count_mutation(
+        &mut self,
+        patient: &PatientBox,
+        targets: &[Target],
+        collision: &CollisionModel,
+        delivery: DeliveryMode,
+        add_prob: f32,
+        remove_prob: f32,
+    ) {
+        let mut rng = crate::random::rng();
+        let draw: f32 = rng.random_range(0.0..1.0);
+        if draw <= add_prob {
+            if self.beams.len() < MAX_BEAMS {
+                self.beams
+                    .extend(delivery.random_beam(patient, targets, collision));
             }
+        } else if draw <= add_prob + remove_prob && self.beams.len() > MIN_BEAMS {
+            let idx = rng.random_range(0..self.beams.len());
+            self.beams.remove(idx);
         }
     }
 }
 
-pub fn create_initial_population(pop_size: usize, patient_box: &PatientBox) -> Vec<Indv> {
+pub fn create_initial_population(
+    pop_size: usize,
+    patient_box: &PatientBox,
+    targets: &[Target],
+    collision: &CollisionModel,
+    delivery: DeliveryMode,
+) -> Vec<Indv> {
     let mut population: Vec<Indv> = vec![];
     for _n in 0..pop_size {
         population.push(Indv {
-            beams: generate_beam_entries(&patient_box),
+            beams: delivery.initial_beams(patient_box, targets, collision),
             fitness: 0.0,
         });
     }
     population
 }
 
-pub fn selection(population: &Vec<Indv>, tournament_size: usize) -> Vec<Indv> {
-    let mut rng = rand::rng();
+pub fn selection(population: &[Indv], tournament_size: usize) -> Vec<Indv> {
+    let mut rng = crate::random::rng();
     let mut selection: Vec<Indv> = vec![];
     let required_parents = population.len();
     if required_parents < tournament_size {
@@ -109,53 +253,124 @@ pub fn selection(population: &Vec<Indv>, tournament_size: usize) -> Vec<Indv> {
     selection
 }
 
-pub fn crossover(parent1: &Indv, parent2: &Indv) -> (Indv, Indv) {
-    let mut rng = rand::rng();
+pub fn crossover(
+    parent1: &Indv,
+    parent2: &Indv,
+    patient: &PatientBox,
+    targets: &[Target],
+    collision: &CollisionModel,
+) -> (Indv, Indv) {
+    let mut rng = crate::random::rng();
     let alpha: f32 = rng.random_range(0.0..1.0);
     let child1 = Indv {
-        beams: calculate_beam_crossover(&parent1.beams, &parent2.beams, alpha),
+        beams: calculate_beam_crossover(
+            &parent1.beams,
+            &parent2.beams,
+            alpha,
+            patient,
+            targets,
+            collision,
+        ),
         fitness: 0.0,
     };
     let child2 = Indv {
-        beams: calculate_beam_crossover(&parent2.beams, &parent1.beams, alpha),
+        beams: calculate_beam_crossover(
+            &parent2.beams,
+            &parent1.beams,
+            alpha,
+            patient,
+            targets,
+            collision,
+        ),
         fitness: 0.0,
     };
     (child1, child2)
 }
 
+// Whether two beams can be blended, static entries only on the same
+// face of the patient box so the child stays on its surface
+fn is_crossover_partner(beam: &Beam, p2_beam: &Beam, patient: &PatientBox) -> bool {
+    match (beam, p2_beam) {
+        (Beam::Static { entry: v1, .. }, Beam::Static { entry: v2, .. }) => {
+            entry_face(v1, patient) == entry_face(v2, patient)
+        }
+        (Beam::Arc { .. }, Beam::Arc { .. }) => true,
+        _ => false,
+    }
+}
+
+// Each beam of the first parent is blended with the first unused beam
+// of the second parent it can pair with, beams without a partner are
+// inherited unchanged so the child keeps the first parent's beam count.
+// Blends in a forbidden direction fall back to the first parent's beam.
 pub fn calculate_beam_crossover(
-    p1_beams: &Vec<Vector>,
-    p2_beams: &Vec<Vector>,
+    p1_beams: &[Beam],
+    p2_beams: &[Beam],
     alpha: f32,
-) -> Vec<Vector> {
-    if p1_beams.len() != p2_beams.len() {
-        panic!("Amount of beams for crossover must be equal");
-    }
-    let mut new_beams: Vec<Vector> = vec![];
-    for i in 0..p1_beams.len() {
-        new_beams.push(p1_beams[i].crossover(&p2_beams[i], alpha));
+    patient: &PatientBox,
+    targets: &[Target],
+    collision: &CollisionModel,
+) -> Vec<Beam> {
+    let mut used = vec![false; p2_beams.len()];
+    let mut new_beams: Vec<Beam> = vec![];
+    for beam in p1_beams {
+        let partner = (0..p2_beams.len())
+            .find(|j| !used[*j] && is_crossover_partner(beam, &p2_beams[*j], patient));
+        match partner {
+            Some(j) => {
+                used[j] = true;
+                let child = beam.crossover(&p2_beams[j], alpha);
+                if child.is_feasible(targets, collision) {
+                    new_beams.push(child);
+                } else {
+                    new_beams.push(beam.clone());
+                }
+            }
+            None => new_beams.push(beam.clone()),
+        }
     }
     new_beams
 }
 
+// Best plan of a run with the best fitness of every generation, the
+// latter drawn as the convergence curve in plan reports
+#[derive(Clone)]
+pub struct GaResult {
+    pub best: Indv,
+    pub convergence: Vec<f32>,
+}
+
 const MUTATION_PROB: f32 = 0.025;
 const MUTATION_BOUND: f32 = 10.0;
+const ADD_BEAM_PROB: f32 = 0.02;
+const REMOVE_BEAM_PROB: f32 = 0.02;
+const MIN_BEAMS: usize = 1;
+const MAX_BEAMS: usize = 12;
+const INITIAL_ARCS: usize = 2;
 
-pub fn ga<const N_SIZE: usize>(
-    population_size: usize,
-    generations: usize,
+pub fn ga(
+    config: &GaConfig,
     patient: PatientBox,
-    tumour: TissueBox,
-    mask_holder: Vec<Mask>,
-    tournament_size: usize,
-) {
-    let mut population = create_initial_population(population_size, &patient);
+    targets: Vec<Target>,
+    labels: LabelVolume,
+    objectives: &Objectives,
+    collision: &CollisionModel,
+) -> GaResult {
+    let generations = config.generations;
+    let delivery = config.delivery;
+    let mut population = create_initial_population(
+        config.population_size,
+        &patient,
+        &targets,
+        collision,
+        delivery,
+    );
     let mut best_in_gen: Vec<Indv> = vec![];
     for generation in 0..generations {
         population.par_iter_mut().for_each(|indv| {
-            indv.calculate_fitness::<{ N_SIZE }>(&patient, &tumour, &mask_holder);
-        }); 
-        let reproduce_pop = selection(&population, tournament_size);
+            indv.calculate_fitness(&patient, &targets, &labels, objectives);
+        });
+        let reproduce_pop = selection(&population, config.tournament_size);
         let mut new_pop: Vec<Indv> = vec![];
         let max_idx: usize = reproduce_pop.len();
         let mut idx: usize = 0;
@@ -163,9 +378,24 @@ pub fn ga<const N_SIZE: usize>(
             if (idx + 1) >= max_idx {
                 break;
             }
-            let (mut child1, mut child2) = crossover(&reproduce_pop[idx], &reproduce_pop[idx + 1]);
-            child1.mutation(&patient, MUTATION_PROB, MUTATION_BOUND);
-            child2.mutation(&patient, MUTATION_PROB, MUTATION_BOUND);
+            let (mut child1, mut child2) = crossover(
+                &reproduce_pop[idx],
+                &reproduce_pop[idx + 1],
+                &patient,
+                &targets,
+                collision,
+            );
+            for child in [&mut child1, &mut child2] {
+                child.mutation(&patient, &targets, collision, MUTATION_PROB, MUTATION_BOUND);
+                child.beam_count_mutation(
+                    &patient,
+                    &targets,
+                    collision,
+                    delivery,
+                    ADD_BEAM_PROB,
+                    REMOVE_BEAM_PROB,
+                );
+            }
             new_pop.push(child1);
             new_pop.push(child2);
             idx += 2;
@@ -180,7 +410,7 @@ pub fn ga<const N_SIZE: usize>(
                 .fitness
                 > indv.fitness
             {
-                min_indv = Some(&indv);
+                min_indv = Some(indv);
             }
         }
         new_pop[0] = min_indv.unwrap().clone();
@@ -208,17 +438,43 @@ pub fn ga<const N_SIZE: usize>(
             .fitness
             > indv.fitness
         {
-            min_indv = Some(&indv);
+            min_indv = Some(indv);
         }
     }
 
     println!("Best Solution Len: {}", best_in_gen.len());
     println!("Best Solution: {}", min_indv.unwrap().fitness);
+    println!("Best Solution Beams: {}", min_indv.unwrap().beams.len());
+    GaResult {
+        best: min_indv.unwrap().clone(),
+        convergence: best_in_gen.iter().map(|indv| indv.fitness).collect(),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::beam_utils::{TissueBox, TissueType};
+    use crate::collision::ForbiddenRegion;
+    use crate::labels::OverlapPriority;
+    use crate::primitives::{Cylinder, Sphere, voxelise};
+    use crate::vector::Vector;
+
+    // Small tumour centred at (centre, centre, centre)
+    fn phantom_target(centre: i64) -> Target {
+        Target {
+            tissue: TissueBox {
+                x: centre,
+                y: centre,
+                z: centre,
+                x_width: 2,
+                y_width: 2,
+                z_width: 2,
+                tissue_type: Some(TissueType::Tumour),
+            },
+            prescription: 40.0,
+        }
+    }
 
     #[test]
     fn test_selection() {
@@ -243,4 +499,277 @@ mod tests {
             assert_ne!(indv.fitness, 50.0);
         }
     }
+
+    #[test]
+    fn test_unequal_beam_crossover() {
+        let p1 = vec![
+            Beam::Static {
+                entry: Vector::new(0.0, 10.0, 20.0),
+                target: 0,
+            },
+            Beam::Static {
+                entry: Vector::new(30.0, 0.0, 40.0),
+                target: 0,
+            },
+        ];
+        let p2 = vec![
+            Beam::Static {
+                entry: Vector::new(0.0, 20.0, 10.0),
+                target: 1,
+            },
+            Beam::Static {
+                entry: Vector::new(10.0, 0.0, 20.0),
+                target: 1,
+            },
+            Beam::Arc {
+                arc: GantryArc::new(0.0, 90.0, 10.0, 0.0).unwrap(),
+                target: 1,
+            },
+        ];
+        let patient = PatientBox::new(50, 50, 50);
+        let targets = vec![phantom_target(25); 2];
+        let collision = CollisionModel::default();
+        let child1 = calculate_beam_crossover(&p1, &p2, 0.5, &patient, &targets, &collision);
+        let child2 = calculate_beam_crossover(&p2, &p1, 0.5, &patient, &targets, &collision);
+        assert_eq!(child1.len(), 2);
+        assert_eq!(child2.len(), 3);
+        match &child1[0] {
+            Beam::Static { entry, target } => {
+                assert_eq!(entry.y, 15.0);
+                assert_eq!(*target, 0);
+            }
+            Beam::Arc { .. } => panic!("Static parents must give a static child"),
+        }
+        assert!(matches!(child2[2], Beam::Arc { target: 1, .. }));
+    }
+
+    #[test]
+    fn test_crossover_pairs_beams_by_face() {
+        let patient = PatientBox::new(20, 20, 20);
+        // Same faces in a different order, the second parent also has an
+        // extra front face beam after add and remove mutations
+        let p1 = vec![
+            Beam::Static {
+                entry: Vector::new(0.0, 4.0, 6.0),
+                target: 0,
+            },
+            Beam::Static {
+                entry: Vector::new(8.0, 12.0, 20.0),
+                target: 0,
+            },
+        ];
+        let p2 = vec![
+            Beam::Static {
+                entry: Vector::new(5.0, 0.0, 5.0),
+                target: 0,
+            },
+            Beam::Static {
+                entry: Vector::new(12.0, 8.0, 20.0),
+                target: 0,
+            },
+            Beam::Static {
+                entry: Vector::new(0.0, 8.0, 10.0),
+                target: 0,
+            },
+        ];
+        let targets = vec![phantom_target(10)];
+        let collision = CollisionModel::default();
+        let child = calculate_beam_crossover(&p1, &p2, 0.5, &patient, &targets, &collision);
+        let entries: Vec<Vector> = child
+            .iter()
+            .map(|beam| match beam {
+                Beam::Static { entry, .. } => *entry,
+                Beam::Arc { .. } => panic!("Static parents must give a static child"),
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![Vector::new(0.0, 6.0, 8.0), Vector::new(10.0, 10.0, 20.0)]
+        );
+
+        // A front face beam has no partner and is inherited unchanged
+        let child = calculate_beam_crossover(&p2, &p1, 0.5, &patient, &targets, &collision);
+        assert!(
+            matches!(child[0], Beam::Static { entry, .. } if entry == Vector::new(5.0, 0.0, 5.0))
+        );
+    }
+
+    #[test]
+    fn test_crossover_respects_collision_model() {
+        let patient = PatientBox::new(20, 20, 20);
+        let targets = vec![phantom_target(10)];
+        // Both parents enter the anterior face at an angle, their blend
+        // would come straight in through the forbidden gantry range
+        let collision = CollisionModel::new(vec![ForbiddenRegion {
+            gantry_min: 350.0,
+            gantry_max: 10.0,
+            couch_min: -90.0,
+            couch_max: 90.0,
+        }]);
+        let p1 = vec![Beam::Static {
+            entry: Vector::new(2.0, 0.0, 10.0),
+            target: 0,
+        }];
+        let p2 = vec![Beam::Static {
+            entry: Vector::new(18.0, 0.0, 10.0),
+            target: 0,
+        }];
+        assert!(p1[0].is_feasible(&targets, &collision));
+        assert!(p2[0].is_feasible(&targets, &collision));
+        let child = calculate_beam_crossover(&p1, &p2, 0.5, &patient, &targets, &collision);
+        assert!(child[0].is_feasible(&targets, &collision));
+        assert!(
+            matches!(child[0], Beam::Static { entry, .. } if entry == Vector::new(2.0, 0.0, 10.0))
+        );
+
+        // Feasible blends are kept
+        let child = calculate_beam_crossover(
+            &p1,
+            &p2,
+            0.75,
+            &patient,
+            &targets,
+            &CollisionModel::default(),
+        );
+        assert!(
+            matches!(child[0], Beam::Static { entry, .. } if entry == Vector::new(6.0, 0.0, 10.0))
+        );
+    }
+
+    #[test]
+    fn test_ga_config_validation() {
+        let config = GaConfig {
+            population_size: 4,
+            generations: 2,
+            tournament_size: 2,
+            delivery: DeliveryMode::Static,
+        };
+        assert!(config.validate().is_ok());
+        for invalid in [
+            GaConfig {
+                generations: 0,
+                ..config.clone()
+            },
+            GaConfig {
+                population_size: 1,
+                tournament_size: 1,
+                ..config.clone()
+            },
+            GaConfig {
+                tournament_size: 5,
+                ..config.clone()
+            },
+        ] {
+            assert!(invalid.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_beam_count_mutation() {
+        let patient = PatientBox::new(20, 20, 20);
+        let targets = vec![phantom_target(10)];
+        let collision = CollisionModel::default();
+        let mut indv = Indv {
+            beams: DeliveryMode::Static.initial_beams(&patient, &targets, &collision),
+            fitness: 0.0,
+        };
+        for _ in 0..100 {
+            indv.beam_count_mutation(
+                &patient,
+                &targets,
+                &collision,
+                DeliveryMode::Mixed,
+                1.0,
+                0.0,
+            );
+        }
+        assert_eq!(indv.beams.len(), MAX_BEAMS);
+        for _ in 0..100 {
+            indv.beam_count_mutation(
+                &patient,
+                &targets,
+                &collision,
+                DeliveryMode::Mixed,
+                0.0,
+                1.0,
+            );
+        }
+        assert_eq!(indv.beams.len(), MIN_BEAMS);
+    }
+
+    #[test]
+    fn test_mutation_respects_collision_model() {
+        let patient = PatientBox::new(20, 20, 20);
+        let targets = vec![phantom_target(10)];
+        // Block everything entering from in front of the tumour
+        let collision = CollisionModel::new(vec![ForbiddenRegion {
+            gantry_min: 270.0,
+            gantry_max: 90.0,
+            couch_min: -90.0,
+            couch_max: 90.0,
+        }]);
+        let mut indv = Indv {
+            beams: DeliveryMode::Mixed.initial_beams(&patient, &targets, &collision),
+            fitness: 0.0,
+        };
+        for _ in 0..50 {
+            indv.mutation(&patient, &targets, &collision, 1.0, 10.0);
+        }
+        assert!(!indv.beams.is_empty());
+        for beam in &indv.beams {
+            assert!(beam.is_feasible(&targets, &collision));
+        }
+    }
+
+    #[test]
+    fn test_ga_on_phantom() {
+        const PATIENT: PatientBox = PatientBox::new(16, 16, 16);
+        let targets = vec![Target {
+            tissue: TissueBox {
+                x: 8,
+                y: 8,
+                z: 9,
+                x_width: 6,
+                y_width: 6,
+                z_width: 6,
+                tissue_type: Some(TissueType::Tumour),
+            },
+            prescription: 40.0,
+        }];
+        let mut tumour = voxelise(
+            &Sphere {
+                centre: Vector::new(8.0, 8.0, 9.0),
+                radius: 3.0,
+            },
+            &PATIENT,
+            TissueType::Tumour,
+        );
+        tumour.prescription = Some(40.0);
+        let cord = voxelise(
+            &Cylinder {
+                start: Vector::new(8.0, 0.0, 4.0),
+                end: Vector::new(8.0, 15.0, 4.0),
+                radius: 1.5,
+            },
+            &PATIENT,
+            TissueType::SerialOrgan,
+        );
+        let config = GaConfig {
+            population_size: 4,
+            generations: 2,
+            tournament_size: 2,
+            delivery: DeliveryMode::Mixed,
+        };
+        let result = ga(
+            &config,
+            PATIENT,
+            targets,
+            LabelVolume::from_structures(&[tumour, cord], &PATIENT, &OverlapPriority::TargetWins)
+                .unwrap(),
+            &Objectives::default(),
+            &CollisionModel::default(),
+        );
+        assert_eq!(result.convergence.len(), config.generations);
+        assert!(result.convergence.contains(&result.best.fitness));
+    }
 }
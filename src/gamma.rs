@@ -0,0 +1,252 @@
+use crate::beam_utils::{PatientBox, to_coords, to_index};
+use rayon::prelude::*;
+use std::io;
+use strum_macros::EnumString;
+
+// Gamma map value of voxels below the low dose threshold
+pub const NOT_EVALUATED: f32 = -1.0;
+// The search stops at this many distance-to-agreement radii, larger
+// gamma values are capped here
+const MAX_GAMMA: f32 = 2.0;
+
+// Global scales the dose criterion by the maximum reference dose,
+// local by the reference dose of each voxel
+#[derive(Debug, Clone, Copy, PartialEq, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum GammaNormalisation {
+    Global,
+    Local,
+}
+
+#[derive(Debug, Clone)]
+pub struct GammaCriteria {
+    // Fraction of the normalisation dose, 0.03 for 3%
+    pub dose_difference: f32,
+    // mm
+    pub distance_to_agreement: f32,
+    pub normalisation: GammaNormalisation,
+    // Reference voxels below this fraction of the maximum reference dose
+    // are not evaluated
+    pub low_dose_threshold: f32,
+}
+
+impl GammaCriteria {
+    // A zero dose difference divides by zero and a zero distance leaves
+    // nothing to search, so every voxel would silently fail
+    pub fn validate(&self) -> io::Result<()> {
+        let problem = if !self.dose_difference.is_finite() || self.dose_difference <= 0.0 {
+            "dose difference must be positive"
+        } else if !self.distance_to_agreement.is_finite() || self.distance_to_agreement <= 0.0 {
+            "distance to agreement must be positive"
+        } else if !(0.0..1.0).contains(&self.low_dose_threshold) {
+            "low dose threshold must be at least 0% and below 100%"
+        } else {
+            return Ok(());
+        };
+        Err(io::Error::new(io::ErrorKind::InvalidInput, problem))
+    }
+}
+
+impl Default for GammaCriteria {
+    fn default() -> GammaCriteria {
+        GammaCriteria {
+            dose_difference: 0.03,
+            distance_to_agreement: 3.0,
+            normalisation: GammaNormalisation::Global,
+            low_dose_threshold: 0.1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GammaResult {
+    // Gamma per voxel in dose matrix order, NOT_EVALUATED below the
+    // threshold, can be exported like a dose grid
+    pub gamma: Vec<f32>,
+    pub evaluated: usize,
+    pub passed: usize,
+}
+
+impl GammaResult {
+    pub fn pass_rate(&self) -> f32 {
+        if self.evaluated == 0 {
+            return 0.0;
+        }
+        self.passed as f32 / self.evaluated as f32
+    }
+}
+
+// 3D gamma of `evaluated` against `reference`, both on `p_box`. The
+// evaluated dose is searched at voxel positions only, so the spacing
+// should be well below the distance to agreement. Panics unless both
+// doses cover the whole grid.
+pub fn gamma_analysis(
+    p_box: &PatientBox,
+    reference: &[f32],
+    evaluated: &[f32],
+    criteria: &GammaCriteria,
+) -> GammaResult {
+    let grid_size = p_box.grid_size() as usize;
+    assert!(
+        reference.len() == grid_size && evaluated.len() == grid_size,
+        "gamma needs doses on the same grid, got {} reference and {} evaluated voxels for {}",
+        reference.len(),
+        evaluated.len(),
+        grid_size
+    );
+    let (x_max, y_max, z_max) = (p_box.x_size, p_box.y_size, p_box.z_size);
+    let max_reference = reference.iter().cloned().fold(0.0, f32::max);
+    let threshold = max_reference * criteria.low_dose_threshold;
+
+    // Neighbour offsets within the search radius, nearest first
+    let radius = criteria.distance_to_agreement * MAX_GAMMA;
+    let reach = |spacing: f32| (radius / spacing).floor() as i64;
+    let (rx, ry, rz) = (
+        reach(p_box.spacing.x),
+        reach(p_box.spacing.y),
+        reach(p_box.spacing.z),
+    );
+    let mut offsets: Vec<(i64, i64, i64, f32)> = Vec::new();
+    for dz in -rz..=rz {
+        for dy in -ry..=ry {
+            for dx in -rx..=rx {
+                let distance = ((dx as f32 * p_box.spacing.x).powi(2)
+                    + (dy as f32 * p_box.spacing.y).powi(2)
+                    + (dz as f32 * p_box.spacing.z).powi(2))
+                .sqrt();
+                if distance <= radius {
+                    offsets.push((dx, dy, dz, distance / criteria.distance_to_agreement));
+                }
+            }
+        }
+    }
+    offsets.sort_by(|a, b| a.3.total_cmp(&b.3));
+
+    let gamma: Vec<f32> = (0..reference.len())
+        .into_par_iter()
+        .map(|index| {
+            let reference_dose = reference[index];
+            if reference_dose < threshold || reference_dose <= 0.0 {
+                return NOT_EVALUATED;
+            }
+            let dose_criterion = criteria.dose_difference
+                * match criteria.normalisation {
+                    GammaNormalisation::Global => max_reference,
+                    GammaNormalisation::Local => reference_dose,
+                };
+            let (x, y, z) = to_coords(index as i64, x_max, y_max);
+            let mut best_squared = MAX_GAMMA * MAX_GAMMA;
+            for (dx, dy, dz, distance) in &offsets {
+                if distance * distance >= best_squared {
+                    break;
+                }
+                let (ex, ey, ez) = (x + dx, y + dy, z + dz);
+                if ex < 0 || ey < 0 || ez < 0 || ex >= x_max || ey >= y_max || ez >= z_max {
+                    continue;
+                }
+                let evaluated_dose = evaluated[to_index(
+                    ex as usize,
+                    ey as usize,
+                    ez as usize,
+                    x_max as usize,
+                    y_max as usize,
+                )];
+                let dose_term = (evaluated_dose - reference_dose) / dose_criterion;
+                best_squared = best_squared.min(dose_term * dose_term + distance * distance);
+            }
+            best_squared.sqrt()
+        })
+        .collect();
+
+    let evaluated_voxels = gamma.iter().filter(|value| **value >= 0.0).count();
+    let passed = gamma
+        .iter()
+        .filter(|value| **value >= 0.0 && **value <= 1.0)
+        .count();
+    GammaResult {
+        gamma,
+        evaluated: evaluated_voxels,
+        passed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gamma_analysis() {
+        let patient = PatientBox::new(20, 1, 1);
+        let reference: Vec<f32> = (0..20).map(|x| 10.0 + x as f32).collect();
+        let criteria = GammaCriteria::default();
+
+        let same = gamma_analysis(&patient, &reference, &reference, &criteria);
+        assert_eq!(same.evaluated, 20);
+        assert_eq!(same.pass_rate(), 1.0);
+        assert!(same.gamma.iter().all(|value| *value == 0.0));
+
+        // A 1 mm shift of a 1 per mm gradient is matched one voxel over
+        let shifted: Vec<f32> = (0..20).map(|x| 11.0 + x as f32).collect();
+        let shift = gamma_analysis(&patient, &reference, &shifted, &criteria);
+        assert!((shift.gamma[5] - 1.0 / 3.0).abs() < 1e-6);
+
+        // 0.5 Gy too high on a 10 / 30 Gy step, within 3% of the 30 Gy
+        // maximum but not within 3% of the 10 Gy half
+        let step: Vec<f32> = (0..20).map(|x| if x < 10 { 10.0 } else { 30.0 }).collect();
+        let hot: Vec<f32> = step.iter().map(|dose| dose + 0.5).collect();
+        let global = gamma_analysis(&patient, &step, &hot, &criteria);
+        assert_eq!(global.pass_rate(), 1.0);
+        let local_criteria = GammaCriteria {
+            normalisation: GammaNormalisation::Local,
+            ..GammaCriteria::default()
+        };
+        let local = gamma_analysis(&patient, &step, &hot, &local_criteria);
+        assert_eq!((local.evaluated, local.passed), (20, 10));
+        assert!((local.gamma[0] - 0.5 / 0.3).abs() < 1e-5);
+        let high_only = gamma_analysis(
+            &patient,
+            &step,
+            &hot,
+            &GammaCriteria {
+                low_dose_threshold: 0.5,
+                ..local_criteria
+            },
+        );
+        assert_eq!(high_only.gamma[0], NOT_EVALUATED);
+        assert_eq!(high_only.pass_rate(), 1.0);
+    }
+
+    #[test]
+    fn test_gamma_criteria_validation() {
+        assert!(GammaCriteria::default().validate().is_ok());
+        let invalid = [
+            GammaCriteria {
+                dose_difference: 0.0,
+                ..GammaCriteria::default()
+            },
+            GammaCriteria {
+                distance_to_agreement: -3.0,
+                ..GammaCriteria::default()
+            },
+            GammaCriteria {
+                distance_to_agreement: f32::NAN,
+                ..GammaCriteria::default()
+            },
+            GammaCriteria {
+                low_dose_threshold: f32::INFINITY,
+                ..GammaCriteria::default()
+            },
+        ];
+        for criteria in invalid {
+            let err = criteria.validate().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "same grid")]
+    fn test_gamma_grid_mismatch() {
+        let patient = PatientBox::new(20, 1, 1);
+        gamma_analysis(&patient, &[1.0; 20], &[1.0; 10], &GammaCriteria::default());
+    }
+}
@@ -0,0 +1,287 @@
+use crate::beam_utils::{PatientBox, TissueType, to_coords};
+use crate::mask::{Structure, VoxelMask};
+use crate::nifti::invalid_data;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+// Label of voxels outside the body outline, they carry no cost
+pub const OUTSIDE_BODY: u16 = u16::MAX;
+
+// How a voxel inside several structures is assigned. CountAll keeps
+// the voxel in every structure, the other rules give it to a single
+// winning structure.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum OverlapPriority {
+    #[default]
+    CountAll,
+    TargetWins,
+    SerialOrganWins,
+    // Explicit priority per structure index, the highest wins.
+    // Structures past the end of the list rank 0.
+    Custom(Vec<u32>),
+}
+
+impl OverlapPriority {
+    fn rank(&self, structure_idx: usize, t_type: &TissueType) -> u32 {
+        match self {
+            OverlapPriority::CountAll => 0,
+            OverlapPriority::TargetWins => match t_type {
+                TissueType::Tumour => 3,
+                TissueType::SerialOrgan => 2,
+                TissueType::ParallelOrgan => 1,
+            },
+            OverlapPriority::SerialOrganWins => match t_type {
+                TissueType::SerialOrgan => 3,
+                TissueType::Tumour => 2,
+                TissueType::ParallelOrgan => 1,
+            },
+            OverlapPriority::Custom(ranks) => ranks.get(structure_idx).copied().unwrap_or(0),
+        }
+    }
+
+    // Ties go to the structure listed first
+    fn resolve(&self, members: Vec<usize>, tissue_types: &[TissueType]) -> Vec<usize> {
+        if matches!(self, OverlapPriority::CountAll) || members.len() < 2 {
+            return members;
+        }
+        let mut winner = members[0];
+        for idx in &members[1..] {
+            if self.rank(*idx, &tissue_types[*idx]) > self.rank(winner, &tissue_types[winner]) {
+                winner = *idx;
+            }
+        }
+        vec![winner]
+    }
+}
+
+// Number of voxels shared by two structures before priorities are applied
+#[derive(Debug, Clone, PartialEq)]
+pub struct OverlapReport {
+    pub structure_a: usize,
+    pub structure_b: usize,
+    pub voxels: usize,
+}
+
+// Per voxel structure membership computed once from the masks. Each
+// label indexes a combination of overlapping structures so voxels
+// inside several masks still count towards all of them, label 0 is
+// reserved for voxels outside every structure.
+pub struct LabelVolume {
+    pub patient_box: PatientBox,
+    pub labels: Vec<u16>,
+    pub combinations: Vec<Vec<usize>>,
+    pub tissue_types: Vec<TissueType>,
+    pub prescriptions: Vec<Option<f32>>,
+    pub overlaps: Vec<OverlapReport>,
+    pub body: Option<Arc<VoxelMask>>,
+    pub attenuation: Option<Arc<Vec<f32>>>,
+}
+
+impl LabelVolume {
+    pub fn from_structures<S: Structure>(
+        structures: &[S],
+        p_box: &PatientBox,
+        priority: &OverlapPriority,
+    ) -> io::Result<LabelVolume> {
+        let tissue_types: Vec<TissueType> = structures
+            .iter()
+            .map(|structure| structure.tissue_type().clone())
+            .collect();
+        let mut combinations: Vec<Vec<usize>> = vec![vec![]];
+        let mut lookup: HashMap<Vec<usize>, u16> = HashMap::new();
+        lookup.insert(vec![], 0);
+        let mut overlap_counts: HashMap<(usize, usize), usize> = HashMap::new();
+        let mut labels: Vec<u16> = vec![0; p_box.grid_size() as usize];
+        for (index, label) in labels.iter_mut().enumerate() {
+            let (x, y, z) = to_coords(index as i64, p_box.x_size, p_box.y_size);
+            let members: Vec<usize> = structures
+                .iter()
+                .enumerate()
+                .filter(|(_, structure)| structure.contains(x, y, z))
+                .map(|(idx, _)| idx)
+                .collect();
+            for (i, a) in members.iter().enumerate() {
+                for b in &members[i + 1..] {
+                    *overlap_counts.entry((*a, *b)).or_insert(0) += 1;
+                }
+            }
+            let members = priority.resolve(members, &tissue_types);
+            *label = match lookup.get(&members) {
+                Some(label) => *label,
+                None => {
+                    // The last u16 value is reserved for OUTSIDE_BODY
+                    if combinations.len() >= OUTSIDE_BODY as usize {
+                        return Err(invalid_data(format!(
+                            "more than {} overlapping structure combinations",
+                            OUTSIDE_BODY
+                        )));
+                    }
+                    let new_label = combinations.len() as u16;
+                    lookup.insert(members.clone(), new_label);
+                    combinations.push(members);
+                    new_label
+                }
+            };
+        }
+        let mut overlaps: Vec<OverlapReport> = overlap_counts
+            .into_iter()
+            .map(|((structure_a, structure_b), voxels)| OverlapReport {
+                structure_a,
+                structure_b,
+                voxels,
+            })
+            .collect();
+        overlaps.sort_by_key(|report| (report.structure_a, report.structure_b));
+        Ok(LabelVolume {
+            patient_box: p_box.clone(),
+            labels,
+            combinations,
+            tissue_types,
+            prescriptions: structures
+                .iter()
+                .map(|structure| structure.prescription())
+                .collect(),
+            overlaps,
+            body: None,
+            attenuation: None,
+        })
+    }
+
+    // Restricts planning to the external body contour
+    pub fn with_body(mut self, body: VoxelMask) -> LabelVolume {
+        for (index, label) in self.labels.iter_mut().enumerate() {
+            if !body.contains_index(index) {
+                *label = OUTSIDE_BODY;
+            }
+        }
+        self.body = Some(Arc::new(body));
+        self
+    }
+
+    // Per-voxel attenuation per mm, e.g. from `Calibration::attenuation_map`
+    pub fn with_attenuation(mut self, attenuation: Vec<f32>) -> LabelVolume {
+        self.attenuation = Some(Arc::new(attenuation));
+        self
+    }
+
+    pub fn in_body(&self, index: usize) -> bool {
+        self.labels[index] != OUTSIDE_BODY
+    }
+
+    pub fn in_structure(&self, index: usize, structure_idx: usize) -> bool {
+        self.in_body(index)
+            && self.combinations[self.labels[index] as usize].contains(&structure_idx)
+    }
+
+    pub fn structure_volume(&self, structure_idx: usize) -> usize {
+        (0..self.labels.len())
+            .filter(|index| self.in_structure(*index, structure_idx))
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beam_utils::TissueBox;
+    use crate::mask::Mask;
+
+    #[test]
+    fn test_overlapping_labels() {
+        let patient = PatientBox::new(10, 10, 10);
+        let tumour = TissueBox {
+            x: 4,
+            y: 4,
+            z: 4,
+            x_width: 2,
+            y_width: 2,
+            z_width: 2,
+            tissue_type: Some(TissueType::Tumour),
+        };
+        let organ = TissueBox {
+            x: 6,
+            tissue_type: Some(TissueType::SerialOrgan),
+            ..tumour.clone()
+        };
+        let masks = vec![
+            Mask::from_tissue_box(&tumour, &patient),
+            Mask::from_tissue_box(&organ, &patient),
+        ];
+        let labels =
+            LabelVolume::from_structures(&masks, &patient, &OverlapPriority::CountAll).unwrap();
+        // Empty, tumour only, both and organ only
+        assert_eq!(labels.combinations.len(), 4);
+        assert_eq!(labels.structure_volume(0), 27);
+        assert_eq!(labels.structure_volume(1), 27);
+        assert_eq!(labels.labels.iter().filter(|l| **l == 0).count(), 1000 - 45);
+        assert_eq!(
+            labels.overlaps,
+            vec![OverlapReport {
+                structure_a: 0,
+                structure_b: 1,
+                voxels: 9,
+            }]
+        );
+
+        let target_wins =
+            LabelVolume::from_structures(&masks, &patient, &OverlapPriority::TargetWins).unwrap();
+        assert_eq!(target_wins.structure_volume(0), 27);
+        assert_eq!(target_wins.structure_volume(1), 18);
+        assert_eq!(target_wins.overlaps, labels.overlaps);
+
+        let organ_wins =
+            LabelVolume::from_structures(&masks, &patient, &OverlapPriority::SerialOrganWins)
+                .unwrap();
+        assert_eq!(organ_wins.structure_volume(0), 18);
+        assert_eq!(organ_wins.structure_volume(1), 27);
+
+        let custom =
+            LabelVolume::from_structures(&masks, &patient, &OverlapPriority::Custom(vec![1, 5]))
+                .unwrap();
+        assert_eq!(custom.structure_volume(0), 18);
+        // Too few ranks from a hand written plan, the organ ranks 0
+        let short =
+            LabelVolume::from_structures(&masks, &patient, &OverlapPriority::Custom(vec![1]))
+                .unwrap();
+        assert_eq!(short.structure_volume(0), 27);
+        assert_eq!(short.structure_volume(1), 18);
+
+        // A body ending at x = 4 cuts the tumour in half
+        let body = TissueBox {
+            x: 2,
+            y: 5,
+            z: 5,
+            x_width: 4,
+            y_width: 10,
+            z_width: 10,
+            tissue_type: Some(TissueType::ParallelOrgan),
+        };
+        let body = VoxelMask::from_mask(&Mask::from_tissue_box(&body, &patient), &patient);
+        let inside = labels.with_body(body);
+        assert_eq!(inside.structure_volume(0), 18);
+        assert_eq!(inside.structure_volume(1), 0);
+    }
+
+    #[test]
+    fn test_too_many_combinations() {
+        // Voxel i is in structure k when bit k of i is set, so every one
+        // of the 2^16 combinations occurs
+        let patient = PatientBox::new(256, 256, 1);
+        let masks: Vec<VoxelMask> = (0..16)
+            .map(|bit| {
+                let mut mask = VoxelMask::new(&patient, TissueType::ParallelOrgan);
+                for index in 0..patient.grid_size() {
+                    if index >> bit & 1 == 1 {
+                        let (x, y, z) = to_coords(index, 256, 256);
+                        mask.set(x, y, z, true);
+                    }
+                }
+                mask
+            })
+            .collect();
+        let labels = LabelVolume::from_structures(&masks, &patient, &OverlapPriority::CountAll);
+        assert!(labels.is_err_and(|err| err.kind() == io::ErrorKind::InvalidData));
+    }
+}
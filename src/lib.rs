@@ -1,4 +1,22 @@
+pub mod arc;
 pub mod beam_utils;
+pub mod calibration;
+pub mod collision;
+pub mod dicom;
+pub mod dvh;
+pub mod export;
 pub mod ga;
+pub mod gamma;
+pub mod labels;
 pub mod mask;
+pub mod mesh;
+pub mod nifti;
+pub mod plan;
+pub mod primitives;
+pub mod quality;
+pub mod random;
+pub mod render;
+pub mod report;
+pub mod rt_export;
+pub mod scenario;
 pub mod vector;
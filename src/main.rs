@@ -1,61 +1,507 @@
+use clap::{Parser, Subcommand};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::time::Instant;
-use tumour_nuker::beam_utils::{PatientBox, TissueBox, TissueType};
+use strum::IntoEnumIterator;
+use tumour_nuker::beam_utils::PatientBox;
+use tumour_nuker::dvh::{Dvh, write_dvh_csv};
+use tumour_nuker::export::{ExportFormat, export_grid};
 use tumour_nuker::ga::ga;
-use tumour_nuker::mask::Mask;
+use tumour_nuker::gamma::{GammaCriteria, GammaNormalisation, gamma_analysis};
+use tumour_nuker::mesh::{Mesh, MeshFormat, isosurface, mask_surface};
+use tumour_nuker::nifti::read_nifti;
+use tumour_nuker::plan::{Plan, load_plan, save_plan};
+use tumour_nuker::random;
+use tumour_nuker::render::{RenderOptions, SliceAxis, render_slice};
+use tumour_nuker::report::write_report;
+use tumour_nuker::scenario::load_scenario;
 
-fn main() {
-    println!("Running Tumour Nuker Optimizer");
-    const PATIENT: PatientBox = PatientBox {
-        x_size: 200,
-        y_size: 400,
-        z_size: 100,
-    };
+#[derive(Parser)]
+#[command(name = "tumour_nuker", about = "Genetic beam placement optimiser")]
+struct Cli {
+    /// Worker threads for the dose calculation, all cores by default
+    #[arg(long, global = true)]
+    threads: Option<usize>,
+    #[command(subcommand)]
+    command: Command,
+}
 
-    let tumour = TissueBox {
-        x: 40,
-        y: 35,
-        z: 12,
-        x_width: 5,
-        y_width: 5,
-        z_width: 5,
-        tissue_type: Some(TissueType::Tumour),
-    };
+#[derive(Subcommand)]
+enum Command {
+    /// Optimise the beams of a scenario and save the best plan
+    Optimize {
+        scenario: PathBuf,
+        #[arg(long, short, default_value = "plan.json")]
+        output: PathBuf,
+        /// Seed for a repeatable run
+        #[arg(long)]
+        seed: Option<u64>,
+        /// Overrides the generation count of the scenario
+        #[arg(long)]
+        generations: Option<usize>,
+        /// Also write an HTML report of the best plan
+        #[arg(long)]
+        report: Option<PathBuf>,
+    },
+    /// Re-score a saved plan against its stored objectives
+    Evaluate { plan: PathBuf },
+    /// Dose volume statistics of every structure in a plan
+    Dvh {
+        plan: PathBuf,
+        /// Also write the cumulative curves as CSV
+        #[arg(long)]
+        csv: Option<PathBuf>,
+        #[arg(long, default_value_t = 0.5)]
+        bin_width: f32,
+    },
+    /// Write the dose grid of a plan
+    ExportDose {
+        plan: PathBuf,
+        #[arg(long, short)]
+        output: PathBuf,
+        /// nifti, nrrd or vtk, taken from the output extension by default
+        #[arg(long)]
+        format: Option<ExportFormat>,
+    },
+    /// Draw dose slices with isodose lines and structure outlines
+    Render {
+        plan: PathBuf,
+        /// PNG, or PPM for a .ppm extension. The axis name is added
+        /// before the extension when all three axes are drawn.
+        #[arg(long, short)]
+        output: PathBuf,
+        /// axial, sagittal or coronal, all three by default
+        #[arg(long)]
+        axis: Option<SliceAxis>,
+        /// Voxel index of the slice, through the first target by default
+        #[arg(long)]
+        slice: Option<i64>,
+        /// Isodose lines as percentages of the prescribed dose
+        #[arg(long, value_delimiter = ',', default_values_t = [50.0, 80.0, 95.0, 107.0])]
+        isodose: Vec<f32>,
+        #[arg(long, default_value_t = 2.0)]
+        pixels_per_mm: f32,
+    },
+    /// Export isodose and structure surfaces as meshes
+    Surfaces {
+        plan: PathBuf,
+        #[arg(long, short, default_value = ".")]
+        output_dir: PathBuf,
+        /// Isodose levels as percentages of the prescribed dose
+        #[arg(long, value_delimiter = ',', default_values_t = [95.0])]
+        isodose: Vec<f32>,
+        /// stl or obj
+        #[arg(long, default_value = "stl")]
+        format: MeshFormat,
+    },
+    /// Write a self-contained HTML report of a plan
+    Report {
+        plan: PathBuf,
+        #[arg(long, short, default_value = "report.html")]
+        output: PathBuf,
+    },
+    /// Gamma analysis of a dose against a reference dose, each given as
+    /// a plan or a NIfTI dose grid
+    Gamma {
+        reference: PathBuf,
+        evaluated: PathBuf,
+        /// Dose difference criterion in percent
+        #[arg(long, default_value_t = 3.0)]
+        dose_difference: f32,
+        /// Distance to agreement in mm
+        #[arg(long, default_value_t = 3.0)]
+        distance: f32,
+        /// global or local
+        #[arg(long, default_value = "global")]
+        normalisation: GammaNormalisation,
+        /// Reference doses below this percentage of the maximum are skipped
+        #[arg(long, default_value_t = 10.0)]
+        threshold: f32,
+        /// Also write the gamma map, format from the extension
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Fitness and dose statistics of two plans side by side
+    Compare { plan_a: PathBuf, plan_b: PathBuf },
+}
 
-    let serial_organ = TissueBox {
-        x: 42,
-        y: 38,
-        z: 18,
-        x_width: 3,
-        y_width: 3,
-        z_width: 3,
-        tissue_type: Some(TissueType::SerialOrgan),
+fn main() {
+    let cli = Cli::parse();
+    if let Some(threads) = cli.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .expect("Thread pool is only built once");
+    }
+    let result = match cli.command {
+        Command::Optimize {
+            scenario,
+            output,
+            seed,
+            generations,
+            report,
+        } => optimize(&scenario, &output, seed, generations, report.as_deref()),
+        Command::Evaluate { plan } => evaluate(&plan),
+        Command::Dvh {
+            plan,
+            csv,
+            bin_width,
+        } => dvh(&plan, csv.as_deref(), bin_width),
+        Command::ExportDose {
+            plan,
+            output,
+            format,
+        } => export_dose(&plan, &output, format),
+        Command::Render {
+            plan,
+            output,
+            axis,
+            slice,
+            isodose,
+            pixels_per_mm,
+        } => render(
+            &plan,
+            &output,
+            axis,
+            slice,
+            RenderOptions {
+                pixels_per_mm,
+                isodose_percentages: isodose,
+                ..RenderOptions::default()
+            },
+        ),
+        Command::Surfaces {
+            plan,
+            output_dir,
+            isodose,
+            format,
+        } => surfaces(&plan, &output_dir, &isodose, format),
+        Command::Report { plan, output } => report(&plan, &output),
+        Command::Gamma {
+            reference,
+            evaluated,
+            dose_difference,
+            distance,
+            normalisation,
+            threshold,
+            output,
+        } => gamma(
+            &reference,
+            &evaluated,
+            &GammaCriteria {
+                dose_difference: dose_difference / 100.0,
+                distance_to_agreement: distance,
+                normalisation,
+                low_dose_threshold: threshold / 100.0,
+            },
+            output.as_deref(),
+        ),
+        Command::Compare { plan_a, plan_b } => compare(&plan_a, &plan_b),
     };
+    if let Err(err) = result {
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    }
+}
 
-    let parallel_organ = TissueBox {
-        x: 50,
-        y: 20,
-        z: 12,
-        x_width: 30,
-        y_width: 15,
-        z_width: 10,
-        tissue_type: Some(TissueType::ParallelOrgan),
-    };
+fn open_plan(path: &Path) -> io::Result<Plan> {
+    load_plan(path)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))
+}
+
+fn optimize(
+    path: &Path,
+    output: &Path,
+    seed: Option<u64>,
+    generations: Option<usize>,
+    report: Option<&Path>,
+) -> io::Result<()> {
+    println!("Running Tumour Nuker Optimizer");
+    let mut scenario = load_scenario(path)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+    if let Some(generations) = generations {
+        scenario.ga.generations = generations;
+    }
+    scenario.ga.validate()?;
+    if let Some(seed) = seed {
+        random::seed(seed);
+    }
 
-    let mut mask_holder: Vec<Mask> = vec![];
-    mask_holder.push(Mask::from_tissue_box(&tumour, &PATIENT));
-    mask_holder.push(Mask::from_tissue_box(&serial_organ, &PATIENT));
-    mask_holder.push(Mask::from_tissue_box(&parallel_organ, &PATIENT));
+    let labels = scenario.labels()?;
+    for overlap in &labels.overlaps {
+        println!(
+            "Structures {} and {} overlap in {} voxels",
+            scenario.structures[overlap.structure_a].name,
+            scenario.structures[overlap.structure_b].name,
+            overlap.voxels
+        );
+    }
 
-    const N_SIZE: usize = PATIENT.grid_size() as usize;
+    let n_size = scenario.patient.grid_size() as usize;
     println!(
         "Rough Memory Size of Dose Matrix: {} MB",
-        (N_SIZE * std::mem::size_of::<[f32; 1]>()) / 1024 / 1024
+        (n_size * std::mem::size_of::<[f32; 1]>()) / 1024 / 1024
     );
 
     let now = Instant::now();
-    ga::<{ N_SIZE }>(20, 10, PATIENT, tumour, mask_holder, 5);
+    let result = ga(
+        &scenario.ga,
+        scenario.patient.clone(),
+        scenario.targets(),
+        labels,
+        &scenario.objectives,
+        &scenario.collision,
+    );
     println!(
         "Time Taken Compute cost and total: {} Miliseconds",
         now.elapsed().as_millis()
     );
+    let plan = scenario.plan(&result);
+    print_indices(&plan, &plan.dose()?)?;
+    save_plan(output, &plan)?;
+    println!("Saved plan to {}", output.display());
+    if let Some(report) = report {
+        write_report(report, &plan, &path.display().to_string())?;
+        println!("Saved report to {}", report.display());
+    }
+    Ok(())
+}
+
+fn evaluate(path: &Path) -> io::Result<()> {
+    let plan = open_plan(path)?;
+    println!("Beams: {}", plan.beams.len());
+    println!("Recorded fitness: {}", plan.fitness);
+    println!("Evaluated fitness: {}", plan.evaluate()?);
+    print_indices(&plan, &plan.dose()?)?;
+    Ok(())
+}
+
+fn print_indices(plan: &Plan, dose: &[f32]) -> io::Result<()> {
+    println!(
+        "{:<20} {:>8} {:>8} {:>8} {:>8}",
+        "Target", "RTOG CI", "Paddick", "HI", "GI"
+    );
+    for (name, indices) in plan.indices(dose)? {
+        println!(
+            "{:<20} {:>8.3} {:>8.3} {:>8.3} {:>8.3}",
+            name, indices.rtog_ci, indices.paddick_ci, indices.homogeneity, indices.gradient
+        );
+    }
+    Ok(())
+}
+
+fn print_dvhs(dvhs: &[Dvh]) {
+    println!(
+        "{:<20} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}",
+        "Structure", "Voxels", "Min", "Mean", "Max", "D95", "D2", "V100%"
+    );
+    for dvh in dvhs {
+        let coverage = match dvh.prescription {
+            Some(prescription) => format!("{:.3}", dvh.volume_at_dose(prescription)),
+            None => "-".to_string(),
+        };
+        println!(
+            "{:<20} {:>8} {:>8.2} {:>8.2} {:>8.2} {:>8.2} {:>8.2} {:>8}",
+            dvh.name,
+            dvh.doses.len(),
+            dvh.min(),
+            dvh.mean(),
+            dvh.max(),
+            dvh.dose_at_volume(0.95),
+            dvh.dose_at_volume(0.02),
+            coverage
+        );
+    }
+}
+
+fn dvh(path: &Path, csv: Option<&Path>, bin_width: f32) -> io::Result<()> {
+    if bin_width.is_nan() || bin_width <= 0.0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--bin-width must be positive",
+        ));
+    }
+    let plan = open_plan(path)?;
+    let dvhs = plan.dvhs(&plan.dose()?);
+    print_dvhs(&dvhs);
+    if let Some(csv) = csv {
+        write_dvh_csv(csv, &dvhs, bin_width)?;
+        println!("Saved DVH curves to {}", csv.display());
+    }
+    Ok(())
+}
+
+fn export_dose(path: &Path, output: &Path, format: Option<ExportFormat>) -> io::Result<()> {
+    let plan = open_plan(path)?;
+    let format = format
+        .or(ExportFormat::from_path(output))
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "can't tell the format from the output name, pass --format",
+        ))?;
+    export_grid(output, format, &plan.patient, &plan.dose()?, "dose")?;
+    println!("Saved dose to {}", output.display());
+    Ok(())
+}
+
+fn render(
+    path: &Path,
+    output: &Path,
+    axis: Option<SliceAxis>,
+    slice: Option<i64>,
+    mut options: RenderOptions,
+) -> io::Result<()> {
+    let plan = open_plan(path)?;
+    options.reference_dose = plan.objectives.default_prescription;
+    let dose = plan.dose()?;
+    let p_box = &plan.patient;
+    let centre = plan.view_centre();
+    let axes: Vec<SliceAxis> = match axis {
+        Some(axis) => vec![axis],
+        None => SliceAxis::iter().collect(),
+    };
+    for axis in &axes {
+        let slice = slice.unwrap_or(axis.slice_through(p_box, &centre));
+        let image = render_slice(p_box, &dose, &plan.structures, *axis, slice, &options);
+        let path = if axes.len() == 1 {
+            output.to_path_buf()
+        } else {
+            let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+            let extension = output
+                .extension()
+                .unwrap_or("png".as_ref())
+                .to_string_lossy();
+            output.with_file_name(format!("{}_{}.{}", stem, axis, extension))
+        };
+        image.write(&path)?;
+        println!("Saved {} slice {} to {}", axis, slice, path.display());
+    }
+    Ok(())
+}
+
+fn surfaces(path: &Path, output_dir: &Path, isodose: &[f32], format: MeshFormat) -> io::Result<()> {
+    let plan = open_plan(path)?;
+    let dose = plan.dose()?;
+    let mut meshes: Vec<(String, Mesh)> = isodose
+        .iter()
+        .map(|percentage| {
+            let level = plan.objectives.default_prescription * percentage / 100.0;
+            (
+                format!("isodose_{}", percentage),
+                isosurface(&plan.patient, &dose, level),
+            )
+        })
+        .collect();
+    for (idx, mask) in plan.structures.iter().enumerate() {
+        meshes.push((plan.structure_name(idx), mask_surface(mask)));
+    }
+    for (name, mesh) in &meshes {
+        let file_name: String = name
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let path = output_dir.join(format!("{}.{}", file_name, format.extension()));
+        mesh.write(&path, format, name)?;
+        println!(
+            "Saved {} ({} triangles, {:.2} cm3) to {}",
+            name,
+            mesh.triangles.len(),
+            mesh.volume() / 1000.0,
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+fn report(path: &Path, output: &Path) -> io::Result<()> {
+    let plan = open_plan(path)?;
+    write_report(output, &plan, &path.display().to_string())?;
+    println!("Saved report to {}", output.display());
+    Ok(())
+}
+
+// Dose grid of a plan file, or a grid read from NIfTI
+fn open_dose(path: &Path) -> io::Result<(PatientBox, Vec<f32>)> {
+    if path.extension().is_some_and(|ext| ext == "json") {
+        let plan = open_plan(path)?;
+        let dose = plan.dose()?;
+        Ok((plan.patient, dose))
+    } else {
+        let volume = read_nifti(path)?;
+        Ok((volume.patient_box, volume.data))
+    }
+}
+
+fn gamma(
+    reference_path: &Path,
+    evaluated_path: &Path,
+    criteria: &GammaCriteria,
+    output: Option<&Path>,
+) -> io::Result<()> {
+    criteria.validate()?;
+    let format = output
+        .map(|output| {
+            ExportFormat::from_path(output).ok_or(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "gamma maps are written as .nii, .nii.gz, .nrrd or .vtk",
+            ))
+        })
+        .transpose()?;
+    let (p_box, reference) = open_dose(reference_path)?;
+    let (evaluated_box, evaluated) = open_dose(evaluated_path)?;
+    if !p_box.same_grid(&evaluated_box) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "dose grids need the same size, spacing and origin",
+        ));
+    }
+    let result = gamma_analysis(&p_box, &reference, &evaluated, criteria);
+    println!(
+        "Gamma {}%/{}mm {:?}: {} of {} voxels pass ({:.2}%)",
+        criteria.dose_difference * 100.0,
+        criteria.distance_to_agreement,
+        criteria.normalisation,
+        result.passed,
+        result.evaluated,
+        result.pass_rate() * 100.0
+    );
+    if let (Some(output), Some(format)) = (output, format) {
+        export_grid(output, format, &p_box, &result.gamma, "gamma")?;
+        println!("Saved gamma map to {}", output.display());
+    }
+    Ok(())
+}
+
+fn compare(path_a: &Path, path_b: &Path) -> io::Result<()> {
+    let plan_a = open_plan(path_a)?;
+    let plan_b = open_plan(path_b)?;
+    let dose_a = plan_a.dose()?;
+    let dose_b = plan_b.dose()?;
+    println!(
+        "Fitness: {} vs {} ({} vs {} beams)",
+        plan_a.evaluate()?,
+        plan_b.evaluate()?,
+        plan_a.beams.len(),
+        plan_b.beams.len()
+    );
+    println!("\n{}", path_a.display());
+    print_dvhs(&plan_a.dvhs(&dose_a));
+    println!("\n{}", path_b.display());
+    print_dvhs(&plan_b.dvhs(&dose_b));
+    if plan_a.patient == plan_b.patient {
+        let max_difference = dose_a
+            .iter()
+            .zip(&dose_b)
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max);
+        println!("\nMax dose difference: {:.3}", max_difference);
+    }
+    Ok(())
 }
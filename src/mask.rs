@@ -1,6 +1,30 @@
-use crate::beam_utils::{PatientBox, TissueBox, TissueType};
+use crate::beam_utils::{PatientBox, Target, TissueBox, TissueType, to_coords, to_index};
+use crate::vector::Vector;
+use serde::{Deserialize, Serialize};
 use std::cmp;
 
+// Common interface for anything that can be planned on, axis-aligned
+// boxes as well as voxel bitmasks of irregular contours
+pub trait Structure: Send + Sync {
+    fn contains(&self, x: i64, y: i64, z: i64) -> bool;
+    fn tissue_type(&self) -> &TissueType;
+    fn prescription(&self) -> Option<f32>;
+}
+
+impl<T: Structure + ?Sized> Structure for Box<T> {
+    fn contains(&self, x: i64, y: i64, z: i64) -> bool {
+        (**self).contains(x, y, z)
+    }
+
+    fn tissue_type(&self) -> &TissueType {
+        (**self).tissue_type()
+    }
+
+    fn prescription(&self) -> Option<f32> {
+        (**self).prescription()
+    }
+}
+
 pub struct MaskHolder {
     pub masks: Vec<Mask>,
 }
@@ -13,32 +37,335 @@ pub struct Mask {
     pub z0: i64,
     pub z1: i64,
     pub t_type: TissueType,
+    pub prescription: Option<f32>,
 }
 
 impl Mask {
+    // The box is given in mm, the mask keeps the voxels whose
+    // positions fall inside it
     pub fn from_tissue_box(t_box: &TissueBox, p_box: &PatientBox) -> Mask {
+        let lower = |c: i64, w: i64, spacing: f32| ((c - w / 2) as f32 / spacing).ceil() as i64;
+        let upper = |c: i64, w: i64, spacing: f32| ((c + w / 2) as f32 / spacing).floor() as i64;
         Mask {
-            x0: cmp::max(0, t_box.x - t_box.x_width / 2),
-            x1: cmp::min(p_box.x_size, t_box.x + t_box.x_width / 2),
-            y0: cmp::max(0, t_box.y - t_box.y_width / 2),
-            y1: cmp::min(p_box.y_size, t_box.y + t_box.y_width / 2),
-            z0: cmp::max(0, t_box.z - t_box.z_width / 2),
-            z1: cmp::min(p_box.z_size, t_box.z + t_box.z_width / 2),
+            x0: cmp::max(0, lower(t_box.x, t_box.x_width, p_box.spacing.x)),
+            x1: cmp::min(p_box.x_size, upper(t_box.x, t_box.x_width, p_box.spacing.x)),
+            y0: cmp::max(0, lower(t_box.y, t_box.y_width, p_box.spacing.y)),
+            y1: cmp::min(p_box.y_size, upper(t_box.y, t_box.y_width, p_box.spacing.y)),
+            z0: cmp::max(0, lower(t_box.z, t_box.z_width, p_box.spacing.z)),
+            z1: cmp::min(p_box.z_size, upper(t_box.z, t_box.z_width, p_box.spacing.z)),
             t_type: t_box.tissue_type.as_ref().unwrap().clone(),
+            prescription: None,
+        }
+    }
+
+    pub fn from_target(target: &Target, p_box: &PatientBox) -> Mask {
+        Mask {
+            prescription: Some(target.prescription),
+            ..Mask::from_tissue_box(&target.tissue, p_box)
         }
     }
 
     pub fn bound_check(&self, x: i64, y: i64, z: i64) -> bool {
-        if x >= self.x0
-            && x <= self.x1
-            && y >= self.y0
-            && y <= self.y1
-            && z >= self.z0
-            && z <= self.z1
-        {
-            true
-        } else {
-            false
+        x >= self.x0 && x <= self.x1 && y >= self.y0 && y <= self.y1 && z >= self.z0 && z <= self.z1
+    }
+}
+
+impl Structure for Mask {
+    fn contains(&self, x: i64, y: i64, z: i64) -> bool {
+        self.bound_check(x, y, z)
+    }
+
+    fn tissue_type(&self) -> &TissueType {
+        &self.t_type
+    }
+
+    fn prescription(&self) -> Option<f32> {
+        self.prescription
+    }
+}
+
+// Margin radii per axis in mm, used as the semi-axes of an
+// ellipsoidal structuring element
+#[derive(Debug, Clone, Copy)]
+pub struct Margin {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Margin {
+    pub fn isotropic(margin: f32) -> Margin {
+        Margin {
+            x: margin,
+            y: margin,
+            z: margin,
+        }
+    }
+
+    // Voxel offsets covered by the margin on a grid with the given spacing
+    fn offsets(&self, spacing: &Vector) -> Vec<(i64, i64, i64)> {
+        let (rx, ry, rz) = (
+            (self.x / spacing.x).floor() as i64,
+            (self.y / spacing.y).floor() as i64,
+            (self.z / spacing.z).floor() as i64,
+        );
+        let axis = |d: i64, r: f32, spacing: f32| {
+            if r > 0.0 {
+                (d as f32 * spacing / r).powf(2.0)
+            } else {
+                0.0
+            }
+        };
+        let mut offsets = vec![];
+        for dx in -rx..=rx {
+            for dy in -ry..=ry {
+                for dz in -rz..=rz {
+                    if axis(dx, self.x, spacing.x)
+                        + axis(dy, self.y, spacing.y)
+                        + axis(dz, self.z, spacing.z)
+                        <= 1.0
+                    {
+                        offsets.push((dx, dy, dz));
+                    }
+                }
+            }
         }
+        offsets
+    }
+}
+
+// Bitset over the dose grid for structures that aren't axis-aligned
+// boxes, bit order follows the dose matrix indexing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(into = "VoxelMaskRecord", from = "VoxelMaskRecord")]
+pub struct VoxelMask {
+    pub patient_box: PatientBox,
+    pub t_type: TissueType,
+    pub prescription: Option<f32>,
+    bits: Vec<u64>,
+}
+
+impl VoxelMask {
+    pub fn new(p_box: &PatientBox, t_type: TissueType) -> VoxelMask {
+        VoxelMask {
+            patient_box: p_box.clone(),
+            t_type,
+            prescription: None,
+            bits: vec![0u64; (p_box.grid_size() as usize).div_ceil(64)],
+        }
+    }
+
+    pub fn from_mask(mask: &Mask, p_box: &PatientBox) -> VoxelMask {
+        let mut voxel_mask = VoxelMask::new(p_box, mask.t_type.clone());
+        voxel_mask.prescription = mask.prescription;
+        for x in mask.x0..=mask.x1.min(p_box.x_size - 1) {
+            for y in mask.y0..=mask.y1.min(p_box.y_size - 1) {
+                for z in mask.z0..=mask.z1.min(p_box.z_size - 1) {
+                    voxel_mask.set(x, y, z, true);
+                }
+            }
+        }
+        voxel_mask
+    }
+
+    fn index(&self, x: i64, y: i64, z: i64) -> Option<usize> {
+        let p_box = &self.patient_box;
+        if x < 0 || y < 0 || z < 0 || x >= p_box.x_size || y >= p_box.y_size || z >= p_box.z_size {
+            return None;
+        }
+        Some(to_index(
+            x as usize,
+            y as usize,
+            z as usize,
+            p_box.x_size as usize,
+            p_box.y_size as usize,
+        ))
+    }
+
+    pub fn contains_index(&self, index: usize) -> bool {
+        self.bits[index / 64] & (1u64 << (index % 64)) != 0
+    }
+
+    pub fn contains(&self, x: i64, y: i64, z: i64) -> bool {
+        self.index(x, y, z)
+            .is_some_and(|index| self.contains_index(index))
+    }
+
+    pub fn set(&mut self, x: i64, y: i64, z: i64, value: bool) {
+        if let Some(index) = self.index(x, y, z) {
+            if value {
+                self.bits[index / 64] |= 1u64 << (index % 64);
+            } else {
+                self.bits[index / 64] &= !(1u64 << (index % 64));
+            }
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.bits
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn voxels(&self) -> impl Iterator<Item = (i64, i64, i64)> + '_ {
+        let (x_max, y_max) = (self.patient_box.x_size, self.patient_box.y_size);
+        (0..self.patient_box.grid_size() as usize)
+            .filter(|index| self.contains_index(*index))
+            .map(move |index| to_coords(index as i64, x_max, y_max))
+    }
+
+    // Smallest box in mm covering the mask, used to aim beams at
+    // structures read from files
+    pub fn bounding_box(&self) -> Option<TissueBox> {
+        let mut voxels = self.voxels();
+        let first = voxels.next()?;
+        let (lo, hi) = voxels.fold((first, first), |(lo, hi), (x, y, z)| {
+            (
+                (lo.0.min(x), lo.1.min(y), lo.2.min(z)),
+                (hi.0.max(x), hi.1.max(y), hi.2.max(z)),
+            )
+        });
+        let lower = self.patient_box.voxel_position(lo.0, lo.1, lo.2);
+        let upper = self.patient_box.voxel_position(hi.0, hi.1, hi.2);
+        Some(TissueBox {
+            x: ((lower.x + upper.x) / 2.0).round() as i64,
+            y: ((lower.y + upper.y) / 2.0).round() as i64,
+            z: ((lower.z + upper.z) / 2.0).round() as i64,
+            x_width: (upper.x - lower.x).ceil() as i64,
+            y_width: (upper.y - lower.y).ceil() as i64,
+            z_width: (upper.z - lower.z).ceil() as i64,
+            tissue_type: Some(self.t_type.clone()),
+        })
+    }
+
+    // Morphological dilation, e.g. CTV to PTV or OAR to PRV
+    pub fn expand(&self, margin: &Margin) -> VoxelMask {
+        let offsets = margin.offsets(&self.patient_box.spacing);
+        let mut expanded = self.clone();
+        for (x, y, z) in self.voxels() {
+            for (dx, dy, dz) in &offsets {
+                expanded.set(x + dx, y + dy, z + dz, true);
+            }
+        }
+        expanded
+    }
+
+    // Morphological erosion, voxels outside the grid count as outside the mask
+    pub fn contract(&self, margin: &Margin) -> VoxelMask {
+        let offsets = margin.offsets(&self.patient_box.spacing);
+        let mut contracted = self.clone();
+        for (x, y, z) in self.voxels() {
+            if !offsets
+                .iter()
+                .all(|(dx, dy, dz)| self.contains(x + dx, y + dy, z + dz))
+            {
+                contracted.set(x, y, z, false);
+            }
+        }
+        contracted
+    }
+}
+
+// Serialised form of a VoxelMask, set voxels are stored as runs of
+// (first index, length) to keep plan files small
+#[derive(Serialize, Deserialize)]
+struct VoxelMaskRecord {
+    patient_box: PatientBox,
+    t_type: TissueType,
+    prescription: Option<f32>,
+    runs: Vec<(usize, usize)>,
+}
+
+impl From<VoxelMask> for VoxelMaskRecord {
+    fn from(mask: VoxelMask) -> VoxelMaskRecord {
+        let mut runs: Vec<(usize, usize)> = vec![];
+        for index in 0..mask.patient_box.grid_size() as usize {
+            if !mask.contains_index(index) {
+                continue;
+            }
+            match runs.last_mut() {
+                Some((start, length)) if *start + *length == index => *length += 1,
+                _ => runs.push((index, 1)),
+            }
+        }
+        VoxelMaskRecord {
+            patient_box: mask.patient_box,
+            t_type: mask.t_type,
+            prescription: mask.prescription,
+            runs,
+        }
+    }
+}
+
+impl From<VoxelMaskRecord> for VoxelMask {
+    fn from(record: VoxelMaskRecord) -> VoxelMask {
+        let mut mask = VoxelMask::new(&record.patient_box, record.t_type);
+        mask.prescription = record.prescription;
+        let grid_size = record.patient_box.grid_size() as usize;
+        for (start, length) in record.runs {
+            for index in start..(start + length).min(grid_size) {
+                mask.bits[index / 64] |= 1u64 << (index % 64);
+            }
+        }
+        mask
+    }
+}
+
+impl Structure for VoxelMask {
+    fn contains(&self, x: i64, y: i64, z: i64) -> bool {
+        VoxelMask::contains(self, x, y, z)
+    }
+
+    fn tissue_type(&self) -> &TissueType {
+        &self.t_type
+    }
+
+    fn prescription(&self) -> Option<f32> {
+        self.prescription
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_margin_expand_contract() {
+        let patient = PatientBox::new(20, 20, 20);
+        let ctv = TissueBox {
+            x: 10,
+            y: 10,
+            z: 10,
+            x_width: 4,
+            y_width: 4,
+            z_width: 4,
+            tissue_type: Some(TissueType::Tumour),
+        };
+        let ctv_mask = VoxelMask::from_mask(&Mask::from_tissue_box(&ctv, &patient), &patient);
+        assert_eq!(ctv_mask.count(), 125);
+
+        // A one voxel isotropic margin adds a slab on each face of the cube
+        let ptv = ctv_mask.expand(&Margin::isotropic(1.0));
+        assert_eq!(ptv.count(), 125 + 6 * 25);
+        assert_eq!(ptv.contract(&Margin::isotropic(1.0)).count(), 125);
+
+        let anisotropic = ctv_mask.expand(&Margin {
+            x: 2.0,
+            y: 0.0,
+            z: 0.0,
+        });
+        assert_eq!(anisotropic.count(), 9 * 5 * 5);
+        assert_eq!(ctv_mask.contract(&Margin::isotropic(2.0)).count(), 1);
+
+        // On a 2 mm grid the same 4 mm box covers 3 voxels per axis and a
+        // 2 mm margin grows it by one voxel
+        let coarse = PatientBox::new(10, 10, 10).with_spacing(Vector::new(2.0, 2.0, 2.0));
+        let coarse_ctv = VoxelMask::from_mask(&Mask::from_tissue_box(&ctv, &coarse), &coarse);
+        assert_eq!(coarse_ctv.count(), 27);
+        assert_eq!(
+            coarse_ctv.expand(&Margin::isotropic(2.0)).count(),
+            27 + 6 * 9
+        );
     }
 }
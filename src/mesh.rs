@@ -0,0 +1,597 @@
+use crate::beam_utils::{PatientBox, TissueType, to_index};
+use crate::export::mask_grid;
+use crate::mask::VoxelMask;
+use crate::nifti::invalid_data;
+use crate::vector::Vector;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use strum_macros::EnumString;
+
+// Each grid cube is split into six tetrahedra around its 0-7 diagonal.
+// Corner i of a cube sits at offset (i & 1, i >> 1 & 1, i >> 2 & 1), and
+// neighbouring cubes split their shared faces along the same diagonal so
+// the surface comes out closed.
+const CUBE_TETRAHEDRA: [[usize; 4]; 6] = [
+    [0, 7, 1, 3],
+    [0, 7, 3, 2],
+    [0, 7, 2, 6],
+    [0, 7, 6, 4],
+    [0, 7, 4, 5],
+    [0, 7, 5, 1],
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum MeshFormat {
+    Stl,
+    Obj,
+}
+
+impl MeshFormat {
+    pub fn from_path(path: &Path) -> Option<MeshFormat> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "stl" => Some(MeshFormat::Stl),
+            "obj" => Some(MeshFormat::Obj),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            MeshFormat::Stl => "stl",
+            MeshFormat::Obj => "obj",
+        }
+    }
+}
+
+// Indexed triangle mesh in scanner coordinates (mm), triangles wind
+// counter-clockwise seen from outside
+#[derive(Debug, Clone, Default)]
+pub struct Mesh {
+    pub vertices: Vec<Vector>,
+    pub triangles: Vec<[usize; 3]>,
+}
+
+fn sub(a: &Vector, b: &Vector) -> Vector {
+    Vector::new(a.x - b.x, a.y - b.y, a.z - b.z)
+}
+
+fn cross(a: &Vector, b: &Vector) -> Vector {
+    Vector::new(
+        a.y * b.z - a.z * b.y,
+        a.z * b.x - a.x * b.z,
+        a.x * b.y - a.y * b.x,
+    )
+}
+
+impl Mesh {
+    // Unnormalised, its length is twice the triangle area
+    fn triangle_normal(&self, triangle: &[usize; 3]) -> Vector {
+        let [a, b, c] = triangle.map(|idx| self.vertices[idx]);
+        cross(&sub(&b, &a), &sub(&c, &a))
+    }
+
+    pub fn area(&self) -> f32 {
+        self.triangles
+            .iter()
+            .map(|triangle| self.triangle_normal(triangle).dist_to_beam() / 2.0)
+            .sum()
+    }
+
+    // Enclosed volume in mm3 by the divergence theorem, only meaningful
+    // for closed meshes
+    pub fn volume(&self) -> f32 {
+        self.triangles
+            .iter()
+            .map(|[a, b, c]| {
+                let [a, b, c] = [a, b, c].map(|idx| self.vertices[*idx]);
+                a.dot(&cross(&b, &c)) / 6.0
+            })
+            .sum()
+    }
+
+    // Binary STL
+    pub fn encode_stl(&self, name: &str) -> Vec<u8> {
+        let mut bytes = vec![0u8; 80];
+        let name = name.as_bytes();
+        bytes[..name.len().min(80)].copy_from_slice(&name[..name.len().min(80)]);
+        bytes.extend((self.triangles.len() as u32).to_le_bytes());
+        for triangle in &self.triangles {
+            let normal = self.triangle_normal(triangle);
+            let length = normal.dist_to_beam().max(f32::MIN_POSITIVE);
+            let normal = Vector::new(normal.x / length, normal.y / length, normal.z / length);
+            for point in std::iter::once(normal).chain(triangle.map(|idx| self.vertices[idx])) {
+                for value in [point.x, point.y, point.z] {
+                    bytes.extend(value.to_le_bytes());
+                }
+            }
+            bytes.extend(0u16.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn encode_obj(&self, name: &str) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        writeln!(bytes, "o {}", name)?;
+        for vertex in &self.vertices {
+            writeln!(bytes, "v {} {} {}", vertex.x, vertex.y, vertex.z)?;
+        }
+        for [a, b, c] in &self.triangles {
+            writeln!(bytes, "f {} {} {}", a + 1, b + 1, c + 1)?;
+        }
+        Ok(bytes)
+    }
+
+    pub fn write(&self, path: &Path, format: MeshFormat, name: &str) -> io::Result<()> {
+        match format {
+            MeshFormat::Stl => fs::write(path, self.encode_stl(name)),
+            MeshFormat::Obj => fs::write(path, self.encode_obj(name)?),
+        }
+    }
+}
+
+// Surface where `values` crosses `level`, with voxels at or above the
+// level inside. Outside the grid counts as below the level so surfaces
+// touching the edge are still closed, they are capped at the last voxel.
+pub fn isosurface(p_box: &PatientBox, values: &[f32], level: f32) -> Mesh {
+    let (x_max, y_max, z_max) = (p_box.x_size, p_box.y_size, p_box.z_size);
+    let value = |x: i64, y: i64, z: i64| {
+        if x < 0 || y < 0 || z < 0 || x >= x_max || y >= y_max || z >= z_max {
+            return f32::NEG_INFINITY;
+        }
+        values[to_index(
+            x as usize,
+            y as usize,
+            z as usize,
+            x_max as usize,
+            y_max as usize,
+        )]
+    };
+    // Grid points are numbered with a one voxel border so edge keys stay
+    // unique for the padding outside the grid
+    let point_id = |x: i64, y: i64, z: i64| {
+        ((x + 1) + (y + 1) * (x_max + 2) + (z + 1) * (x_max + 2) * (y_max + 2)) as usize
+    };
+    let position = |x: i64, y: i64, z: i64| {
+        let offset = p_box.voxel_position(x, y, z);
+        Vector::new(
+            p_box.origin.x + offset.x,
+            p_box.origin.y + offset.y,
+            p_box.origin.z + offset.z,
+        )
+    };
+
+    let mut mesh = Mesh::default();
+    let mut edge_vertices: HashMap<(usize, usize), usize> = HashMap::new();
+    for z in -1..z_max {
+        for y in -1..y_max {
+            for x in -1..x_max {
+                let corners: [(i64, i64, i64); 8] = std::array::from_fn(|i| {
+                    (
+                        x + (i & 1) as i64,
+                        y + (i >> 1 & 1) as i64,
+                        z + (i >> 2 & 1) as i64,
+                    )
+                });
+                let corner_values = corners.map(|(cx, cy, cz)| value(cx, cy, cz));
+                if corner_values.iter().all(|v| *v >= level)
+                    || corner_values.iter().all(|v| *v < level)
+                {
+                    continue;
+                }
+
+                // Vertex on the edge between two corners, shared with
+                // every other tetrahedron using that edge
+                let mut edge_vertex = |a: usize, b: usize, mesh: &mut Mesh| {
+                    let (ida, idb) = (
+                        point_id(corners[a].0, corners[a].1, corners[a].2),
+                        point_id(corners[b].0, corners[b].1, corners[b].2),
+                    );
+                    let key = (ida.min(idb), ida.max(idb));
+                    *edge_vertices.entry(key).or_insert_with(|| {
+                        let (va, vb) = (corner_values[a], corner_values[b]);
+                        let t = if va.is_finite() && vb.is_finite() {
+                            ((level - va) / (vb - va)).clamp(0.0, 1.0)
+                        } else if va.is_finite() {
+                            0.0
+                        } else {
+                            1.0
+                        };
+                        let (pa, pb) = (
+                            position(corners[a].0, corners[a].1, corners[a].2),
+                            position(corners[b].0, corners[b].1, corners[b].2),
+                        );
+                        mesh.vertices.push(Vector::new(
+                            pa.x + t * (pb.x - pa.x),
+                            pa.y + t * (pb.y - pa.y),
+                            pa.z + t * (pb.z - pa.z),
+                        ));
+                        mesh.vertices.len() - 1
+                    })
+                };
+
+                for tetrahedron in CUBE_TETRAHEDRA {
+                    let (inside, outside): (Vec<usize>, Vec<usize>) = tetrahedron
+                        .iter()
+                        .partition(|corner| corner_values[**corner] >= level);
+                    let polygon: Vec<usize> = match (inside.len(), outside.len()) {
+                        (1, 3) => outside
+                            .iter()
+                            .map(|o| edge_vertex(inside[0], *o, &mut mesh))
+                            .collect(),
+                        (3, 1) => inside
+                            .iter()
+                            .map(|i| edge_vertex(*i, outside[0], &mut mesh))
+                            .collect(),
+                        // Quad around the two inside corners, ordered so
+                        // consecutive vertices share a corner
+                        (2, 2) => vec![
+                            edge_vertex(inside[0], outside[0], &mut mesh),
+                            edge_vertex(inside[0], outside[1], &mut mesh),
+                            edge_vertex(inside[1], outside[1], &mut mesh),
+                            edge_vertex(inside[1], outside[0], &mut mesh),
+                        ],
+                        _ => continue,
+                    };
+
+                    // Face the triangles away from the inside corners
+                    let centre = |group: &[usize]| {
+                        let points: Vec<Vector> = group
+                            .iter()
+                            .map(|c| position(corners[*c].0, corners[*c].1, corners[*c].2))
+                            .collect();
+                        let n = points.len() as f32;
+                        Vector::new(
+                            points.iter().map(|p| p.x).sum::<f32>() / n,
+                            points.iter().map(|p| p.y).sum::<f32>() / n,
+                            points.iter().map(|p| p.z).sum::<f32>() / n,
+                        )
+                    };
+                    let outward = sub(&centre(&outside), &centre(&inside));
+                    for fan in 1..polygon.len() - 1 {
+                        let mut triangle = [polygon[0], polygon[fan], polygon[fan + 1]];
+                        if mesh.triangle_normal(&triangle).dot(&outward) < 0.0 {
+                            triangle.swap(1, 2);
+                        }
+                        mesh.triangles.push(triangle);
+                    }
+                }
+            }
+        }
+    }
+    mesh
+}
+
+pub fn mask_surface(mask: &VoxelMask) -> Mesh {
+    isosurface(&mask.patient_box, &mask_grid(mask), 0.5)
+}
+
+// Binary STL, or ASCII when the size doesn't match the triangle count
+// in the binary header. Vertices are merged by exact position.
+pub fn parse_stl(bytes: &[u8]) -> io::Result<Mesh> {
+    let mut points: Vec<Vector> = Vec::new();
+    let binary_count = bytes
+        .get(80..84)
+        .map(|count| u32::from_le_bytes(count.try_into().unwrap()) as usize);
+    if binary_count.is_some_and(|count| bytes.len() == 84 + 50 * count) {
+        for record in bytes[84..].chunks(50) {
+            for vertex in 1..4 {
+                let value = |axis: usize| {
+                    f32::from_le_bytes(record[vertex * 12 + axis * 4..][..4].try_into().unwrap())
+                };
+                points.push(Vector::new(value(0), value(1), value(2)));
+            }
+        }
+    } else {
+        let text = std::str::from_utf8(bytes)
+            .map_err(|_| invalid_data("STL is neither binary nor ASCII"))?;
+        for line in text.lines() {
+            let mut tokens = line.split_whitespace();
+            if tokens.next() != Some("vertex") {
+                continue;
+            }
+            let values: Vec<f32> = tokens
+                .map(|token| token.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|_| invalid_data("STL vertex isn't numeric"))?;
+            if values.len() != 3 {
+                return Err(invalid_data("STL vertex needs three coordinates"));
+            }
+            points.push(Vector::new(values[0], values[1], values[2]));
+        }
+        if !points.len().is_multiple_of(3) {
+            return Err(invalid_data("STL facets need three vertices"));
+        }
+    }
+
+    let mut mesh = Mesh::default();
+    let mut lookup: HashMap<[u32; 3], usize> = HashMap::new();
+    let mut corners = points.iter().map(|point| {
+        *lookup
+            .entry([point.x.to_bits(), point.y.to_bits(), point.z.to_bits()])
+            .or_insert_with(|| {
+                mesh.vertices.push(*point);
+                mesh.vertices.len() - 1
+            })
+    });
+    let mut triangles = Vec::new();
+    while let (Some(a), Some(b), Some(c)) = (corners.next(), corners.next(), corners.next()) {
+        triangles.push([a, b, c]);
+    }
+    mesh.triangles = triangles;
+    Ok(mesh)
+}
+
+// `v` and `f` records only, polygons are split into fans and texture or
+// normal indices after a slash are ignored
+pub fn parse_obj(text: &str) -> io::Result<Mesh> {
+    let mut mesh = Mesh::default();
+    for (line_idx, line) in text.lines().enumerate() {
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            Some("v") => {
+                let values: Vec<f32> = tokens
+                    .take(3)
+                    .map(|token| token.parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| {
+                        invalid_data(format!("OBJ line {} isn't numeric", line_idx + 1))
+                    })?;
+                if values.len() != 3 {
+                    return Err(invalid_data(format!(
+                        "OBJ line {} needs x y z",
+                        line_idx + 1
+                    )));
+                }
+                mesh.vertices
+                    .push(Vector::new(values[0], values[1], values[2]));
+            }
+            Some("f") => {
+                let face: Vec<usize> = tokens
+                    .map(|token| {
+                        let index: i64 = token.split('/').next()?.parse().ok()?;
+                        let count = mesh.vertices.len() as i64;
+                        let index = if index < 0 { count + index } else { index - 1 };
+                        (0..count).contains(&index).then_some(index as usize)
+                    })
+                    .collect::<Option<_>>()
+                    .ok_or(invalid_data(format!(
+                        "OBJ line {} has a bad vertex index",
+                        line_idx + 1
+                    )))?;
+                for fan in 1..face.len().saturating_sub(1) {
+                    mesh.triangles.push([face[0], face[fan], face[fan + 1]]);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(mesh)
+}
+
+pub fn read_mesh(path: &Path) -> io::Result<Mesh> {
+    match MeshFormat::from_path(path) {
+        Some(MeshFormat::Stl) => parse_stl(&fs::read(path)?),
+        Some(MeshFormat::Obj) => parse_obj(&fs::read_to_string(path)?),
+        None => Err(invalid_data("mesh files need an .stl or .obj extension")),
+    }
+}
+
+// Twice the signed area of (v0, v1, p) in the xy plane, positive with p
+// left of v0 -> v1. The endpoints are always taken in the same order so
+// both triangles sharing an edge get exactly opposite values.
+fn edge_function(v0: &Vector, v1: &Vector, px: f32, py: f32) -> f32 {
+    let side = |v0: &Vector, v1: &Vector| (v1.x - v0.x) * (py - v0.y) - (v1.y - v0.y) * (px - v0.x);
+    if (v0.x, v0.y) <= (v1.x, v1.y) {
+        side(v0, v1)
+    } else {
+        -side(v1, v0)
+    }
+}
+
+// Top-left rule of a counter-clockwise edge, a column exactly on an
+// edge belongs only to the triangle for which that edge is a left or a
+// top edge, so crossings through shared edges and vertices count once
+fn owns_edge(v0: &Vector, v1: &Vector) -> bool {
+    let (dx, dy) = (v1.x - v0.x, v1.y - v0.y);
+    dy < 0.0 || (dy == 0.0 && dx < 0.0)
+}
+
+// Voxels whose centre is inside a closed mesh given in scanner
+// coordinates. A ray is cast along +z through every voxel column and a
+// voxel is inside when an odd number of surface crossings lie below it.
+pub fn voxelise_mesh(mesh: &Mesh, p_box: &PatientBox, t_type: TissueType) -> VoxelMask {
+    let (x_max, y_max) = (p_box.x_size, p_box.y_size);
+    let local: Vec<Vector> = mesh
+        .vertices
+        .iter()
+        .map(|vertex| {
+            Vector::new(
+                vertex.x - p_box.origin.x,
+                vertex.y - p_box.origin.y,
+                vertex.z - p_box.origin.z,
+            )
+        })
+        .collect();
+
+    let mut crossings: Vec<Vec<f32>> = vec![Vec::new(); (x_max * y_max) as usize];
+    for triangle in &mesh.triangles {
+        let [a, mut b, mut c] = triangle.map(|idx| local[idx]);
+        let area = (b.x - a.x) * (c.y - a.y) - (c.x - a.x) * (b.y - a.y);
+        if area == 0.0 {
+            continue;
+        }
+        // Seen from above every triangle winds counter-clockwise
+        if area < 0.0 {
+            std::mem::swap(&mut b, &mut c);
+        }
+        let edges = [(b, c), (c, a), (a, b)];
+        let owned = edges.map(|(v0, v1)| owns_edge(&v0, &v1));
+        let x_range = (a.x.min(b.x).min(c.x) / p_box.spacing.x).ceil().max(0.0) as i64
+            ..=((a.x.max(b.x).max(c.x) / p_box.spacing.x).floor() as i64).min(x_max - 1);
+        let y_range = (a.y.min(b.y).min(c.y) / p_box.spacing.y).ceil().max(0.0) as i64
+            ..=((a.y.max(b.y).max(c.y) / p_box.spacing.y).floor() as i64).min(y_max - 1);
+        for x in x_range {
+            for y in y_range.clone() {
+                let (px, py) = (x as f32 * p_box.spacing.x, y as f32 * p_box.spacing.y);
+                // Edge values opposite a, b and c, their sum is twice the
+                // area so they double as barycentric weights
+                let weights = edges.map(|(v0, v1)| edge_function(&v0, &v1, px, py));
+                let inside = weights
+                    .iter()
+                    .zip(owned)
+                    .all(|(weight, owned)| *weight > 0.0 || (*weight == 0.0 && owned));
+                if inside {
+                    let [wa, wb, wc] = weights;
+                    let z = (wa * a.z + wb * b.z + wc * c.z) / (wa + wb + wc);
+                    crossings[(x + y * x_max) as usize].push(z);
+                }
+            }
+        }
+    }
+
+    let mut mask = VoxelMask::new(p_box, t_type);
+    for (column, hits) in crossings.iter_mut().enumerate() {
+        hits.sort_by(|a, b| a.total_cmp(b));
+        let (x, y) = (column as i64 % x_max, column as i64 / x_max);
+        for pair in hits.chunks_exact(2) {
+            let first = (pair[0] / p_box.spacing.z).ceil().max(0.0) as i64;
+            let last = ((pair[1] / p_box.spacing.z).floor() as i64).min(p_box.z_size - 1);
+            for z in first..=last {
+                mask.set(x, y, z, true);
+            }
+        }
+    }
+    mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::{Sphere, voxelise};
+
+    #[test]
+    fn test_sphere_isosurface() {
+        let patient = PatientBox::new(24, 24, 12).with_spacing(Vector::new(1.0, 1.0, 2.0));
+        let centre = Vector::new(12.0, 12.0, 12.0);
+        let dose: Vec<f32> = (0..patient.grid_size())
+            .map(|index| {
+                let (x, y, z) = crate::beam_utils::to_coords(index, 24, 24);
+                40.0 - patient.voxel_position(x, y, z).dist_to_vector(&centre)
+            })
+            .collect();
+        let mesh = isosurface(&patient, &dose, 32.0);
+        let exact = 4.0 / 3.0 * std::f32::consts::PI * 8.0f32.powi(3);
+        assert!((mesh.volume() - exact).abs() / exact < 0.05);
+        let exact_area = 4.0 * std::f32::consts::PI * 8.0f32.powi(2);
+        assert!((mesh.area() - exact_area).abs() / exact_area < 0.1);
+
+        // A mask touching the grid edge is capped, so still closed
+        let mask = voxelise(
+            &Sphere {
+                centre: Vector::new(0.0, 12.0, 12.0),
+                radius: 5.0,
+            },
+            &patient,
+            TissueType::Tumour,
+        );
+        let surface = mask_surface(&mask);
+        assert!(surface.volume() > 0.0);
+        let mut edge_uses: HashMap<(usize, usize), i32> = HashMap::new();
+        for [a, b, c] in &surface.triangles {
+            for (p, q) in [(a, b), (b, c), (c, a)] {
+                *edge_uses.entry((*p.min(q), *p.max(q))).or_default() += 1;
+            }
+        }
+        assert!(edge_uses.values().all(|uses| *uses == 2));
+
+        let stl = surface.encode_stl("PTV");
+        assert_eq!(stl.len(), 84 + 50 * surface.triangles.len());
+        assert_eq!(
+            MeshFormat::from_path(Path::new("ptv.OBJ")),
+            Some(MeshFormat::Obj)
+        );
+    }
+
+    #[test]
+    fn test_mesh_import() {
+        let patient = PatientBox::new(20, 20, 10)
+            .with_spacing(Vector::new(1.0, 1.0, 2.0))
+            .with_origin(Vector::new(-10.0, 5.0, 0.0));
+        let tumour = voxelise(
+            &Sphere {
+                centre: Vector::new(10.0, 10.0, 10.0),
+                radius: 5.0,
+            },
+            &patient,
+            TissueType::Tumour,
+        );
+        let surface = mask_surface(&tumour);
+
+        // The 0.5 level surface passes between inside and outside voxel
+        // centres, so voxelising it gives back the same mask
+        let from_stl = parse_stl(&surface.encode_stl("PTV")).unwrap();
+        assert_eq!(from_stl.triangles.len(), surface.triangles.len());
+        let mask = voxelise_mesh(&from_stl, &patient, TissueType::Tumour);
+        assert_eq!(mask.count(), tumour.count());
+        assert!(tumour.voxels().all(|(x, y, z)| mask.contains(x, y, z)));
+
+        let obj =
+            parse_obj(&String::from_utf8(surface.encode_obj("PTV").unwrap()).unwrap()).unwrap();
+        assert_eq!(obj.vertices.len(), surface.vertices.len());
+        let ascii = "solid t\nfacet normal 0 0 1\nouter loop\nvertex 0 0 0\nvertex 1 0 0\n\
+                     vertex 0 1 0\nendloop\nendfacet\nendsolid t\n";
+        assert_eq!(
+            parse_stl(ascii.as_bytes()).unwrap().triangles,
+            vec![[0, 1, 2]]
+        );
+        let quad = parse_obj("v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nf 1/1 2/2 3/3 -1\n").unwrap();
+        assert_eq!(quad.triangles, vec![[0, 1, 2], [0, 2, 3]]);
+    }
+
+    #[test]
+    fn test_voxelise_mesh_on_columns() {
+        // Octahedron |x - 4| + |y - 4| + |z - 4| <= 3 in grid mm, its
+        // vertices and edges lie exactly on voxel columns
+        let patient = PatientBox::new(9, 9, 9).with_origin(Vector::new(-10.0, -10.0, 0.0));
+        let vertices = [
+            (7.0, 4.0, 4.0),
+            (1.0, 4.0, 4.0),
+            (4.0, 7.0, 4.0),
+            (4.0, 1.0, 4.0),
+            (4.0, 4.0, 7.0),
+            (4.0, 4.0, 1.0),
+        ]
+        .map(|(x, y, z)| Vector::new(x - 10.0, y - 10.0, z));
+        let mut triangles = Vec::new();
+        for (x_side, y_side) in [(0, 2), (2, 1), (1, 3), (3, 0)] {
+            triangles.push([x_side, y_side, 4]);
+            triangles.push([y_side, x_side, 5]);
+        }
+        let octahedron = Mesh {
+            vertices: vertices.to_vec(),
+            triangles,
+        };
+        assert!((octahedron.volume() - 36.0).abs() < 1e-3);
+
+        let mask = voxelise_mesh(&octahedron, &patient, TissueType::Tumour);
+        for z in 1..=7 {
+            assert!(mask.contains(4, 4, z), "apex column misses z {}", z);
+        }
+        for x in 0..9 {
+            for y in 0..9 {
+                for z in 0..9 {
+                    let distance = (x - 4i64).abs() + (y - 4i64).abs() + (z - 4i64).abs();
+                    if distance < 3 {
+                        assert!(mask.contains(x, y, z), "({}, {}, {}) is inside", x, y, z);
+                    } else if distance > 3 {
+                        assert!(!mask.contains(x, y, z), "({}, {}, {}) is outside", x, y, z);
+                    }
+                }
+            }
+        }
+    }
+}
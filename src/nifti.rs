@@ -0,0 +1,463 @@
+use crate::beam_utils::{PatientBox, Target, TissueType, to_coords};
+use crate::mask::VoxelMask;
+use crate::vector::Vector;
+use flate2::Compression;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+const HEADER_SIZE: i32 = 348;
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// NIfTI-1 datatype codes
+const DT_UINT8: i16 = 2;
+const DT_INT16: i16 = 4;
+const DT_INT32: i16 = 8;
+const DT_FLOAT32: i16 = 16;
+const DT_FLOAT64: i16 = 64;
+const DT_INT8: i16 = 256;
+const DT_UINT16: i16 = 512;
+const DT_UINT32: i16 = 768;
+
+// First volume of a single-file NIfTI-1 image, samples are scaled by
+// scl_slope/scl_inter and stored in dose matrix order (x fastest)
+#[derive(Debug, Clone)]
+pub struct NiftiVolume {
+    pub patient_box: PatientBox,
+    pub data: Vec<f32>,
+}
+
+// Maps a label value of a segmentation to the structure it describes
+#[derive(Debug, Clone)]
+pub struct LabelSpec {
+    pub label: i32,
+    pub t_type: TissueType,
+    pub prescription: Option<f32>,
+}
+
+// Grid, image intensities and structures read from an imaging study
+#[derive(Debug, Clone)]
+pub struct Anatomy {
+    pub patient_box: PatientBox,
+    // CT numbers in HU, or raw intensities for other images. These are
+    // not densities, `Calibration::attenuation_map` turns them into the
+    // attenuation the dose calculation uses.
+    pub hounsfield: Vec<f32>,
+    pub masks: Vec<VoxelMask>,
+    // Study and frame of reference of a source CT, so exports register
+    // to it. Images without them get new UIDs on export.
+    pub study_uid: Option<String>,
+    pub frame_of_reference_uid: Option<String>,
+}
+
+impl Anatomy {
+    // Every structure with a prescription becomes a target, beams aim
+    // at the centre of its bounding box
+    pub fn targets(&self) -> Vec<Target> {
+        self.masks
+            .iter()
+            .filter_map(|mask| {
+                Some(Target {
+                    prescription: mask.prescription?,
+                    tissue: mask.bounding_box()?,
+                })
+            })
+            .collect()
+    }
+}
+
+impl NiftiVolume {
+    // One mask per label, voxels are matched on the rounded sample value
+    pub fn masks(&self, labels: &[LabelSpec]) -> Vec<VoxelMask> {
+        let (x_max, y_max) = (self.patient_box.x_size, self.patient_box.y_size);
+        labels
+            .iter()
+            .map(|spec| {
+                let mut mask = VoxelMask::new(&self.patient_box, spec.t_type.clone());
+                mask.prescription = spec.prescription;
+                for (index, value) in self.data.iter().enumerate() {
+                    if value.round() as i32 == spec.label {
+                        let (x, y, z) = to_coords(index as i64, x_max, y_max);
+                        mask.set(x, y, z, true);
+                    }
+                }
+                mask
+            })
+            .collect()
+    }
+}
+
+pub(crate) fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+struct Header<'a> {
+    bytes: &'a [u8],
+    big_endian: bool,
+}
+
+impl Header<'_> {
+    fn field<const W: usize>(&self, offset: usize) -> [u8; W] {
+        let mut field: [u8; W] = self.bytes[offset..offset + W].try_into().unwrap();
+        if self.big_endian {
+            field.reverse();
+        }
+        field
+    }
+
+    fn i16(&self, offset: usize) -> i16 {
+        i16::from_le_bytes(self.field(offset))
+    }
+
+    fn f32(&self, offset: usize) -> f32 {
+        f32::from_le_bytes(self.field(offset))
+    }
+}
+
+fn sample_width(datatype: i16) -> io::Result<usize> {
+    match datatype {
+        DT_UINT8 | DT_INT8 => Ok(1),
+        DT_INT16 | DT_UINT16 => Ok(2),
+        DT_INT32 | DT_UINT32 | DT_FLOAT32 => Ok(4),
+        DT_FLOAT64 => Ok(8),
+        _ => Err(invalid_data(format!(
+            "unsupported NIfTI datatype {datatype}"
+        ))),
+    }
+}
+
+fn decode_sample(bytes: &[u8], datatype: i16, big_endian: bool) -> f32 {
+    let mut sample = bytes.to_vec();
+    if big_endian {
+        sample.reverse();
+    }
+    match datatype {
+        DT_UINT8 => sample[0] as f32,
+        DT_INT8 => sample[0] as i8 as f32,
+        DT_INT16 => i16::from_le_bytes([sample[0], sample[1]]) as f32,
+        DT_UINT16 => u16::from_le_bytes([sample[0], sample[1]]) as f32,
+        DT_INT32 => i32::from_le_bytes(sample[..4].try_into().unwrap()) as f32,
+        DT_UINT32 => u32::from_le_bytes(sample[..4].try_into().unwrap()) as f32,
+        DT_FLOAT32 => f32::from_le_bytes(sample[..4].try_into().unwrap()),
+        _ => f64::from_le_bytes(sample[..8].try_into().unwrap()) as f32,
+    }
+}
+
+// Parses an uncompressed single-file (n+1) NIfTI-1 image of either byte order
+pub fn parse_nifti(bytes: &[u8]) -> io::Result<NiftiVolume> {
+    if bytes.len() < HEADER_SIZE as usize {
+        return Err(invalid_data("file shorter than a NIfTI-1 header"));
+    }
+    let sizeof_hdr: [u8; 4] = bytes[0..4].try_into().unwrap();
+    let big_endian = if i32::from_le_bytes(sizeof_hdr) == HEADER_SIZE {
+        false
+    } else if i32::from_be_bytes(sizeof_hdr) == HEADER_SIZE {
+        true
+    } else {
+        return Err(invalid_data("not a NIfTI-1 file"));
+    };
+    if &bytes[344..347] != b"n+1" {
+        return Err(invalid_data(
+            "only single-file (n+1) NIfTI-1 images are supported",
+        ));
+    }
+    let header = Header { bytes, big_endian };
+
+    let n_dims = header.i16(40);
+    if !(1..=7).contains(&n_dims) {
+        return Err(invalid_data(format!(
+            "invalid NIfTI dimension count {n_dims}"
+        )));
+    }
+    let dim = |axis: i16| {
+        if axis <= n_dims {
+            header.i16(40 + 2 * axis as usize)
+        } else {
+            1
+        }
+    };
+    let (x_size, y_size, z_size) = (dim(1) as i64, dim(2) as i64, dim(3) as i64);
+    if x_size < 1 || y_size < 1 || z_size < 1 {
+        return Err(invalid_data("NIfTI image has an empty dimension"));
+    }
+
+    let pixdim = |axis: usize| match header.f32(76 + 4 * axis).abs() {
+        spacing if spacing > 0.0 && spacing.is_finite() => spacing,
+        _ => 1.0,
+    };
+    // Only the translation of the scanner transform is kept, the grid
+    // itself is assumed to be axis-aligned
+    let origin = if header.i16(254) > 0 {
+        Vector::new(header.f32(292), header.f32(308), header.f32(324))
+    } else if header.i16(252) > 0 {
+        Vector::new(header.f32(268), header.f32(272), header.f32(276))
+    } else {
+        Vector::new(0.0, 0.0, 0.0)
+    };
+    let patient_box = PatientBox::new(x_size, y_size, z_size)
+        .with_spacing(Vector::new(pixdim(1), pixdim(2), pixdim(3)))
+        .with_origin(origin);
+
+    let datatype = header.i16(70);
+    let width = sample_width(datatype)?;
+    let (slope, intercept) = match header.f32(112) {
+        slope if slope != 0.0 && slope.is_finite() => (slope, header.f32(116)),
+        _ => (1.0, 0.0),
+    };
+    let vox_offset = header.f32(108);
+    if !vox_offset.is_finite() {
+        return Err(invalid_data("NIfTI vox_offset is not a number"));
+    }
+    let offset = vox_offset.max(HEADER_SIZE as f32) as usize;
+    let n_voxels = patient_box.grid_size() as usize;
+    let end = n_voxels
+        .checked_mul(width)
+        .and_then(|length| length.checked_add(offset))
+        .ok_or_else(|| invalid_data("NIfTI image data is larger than memory"))?;
+    let samples = bytes
+        .get(offset..end)
+        .ok_or_else(|| invalid_data("NIfTI image data is truncated"))?;
+    let data = samples
+        .chunks_exact(width)
+        .map(|sample| decode_sample(sample, datatype, big_endian) * slope + intercept)
+        .collect();
+
+    Ok(NiftiVolume { patient_box, data })
+}
+
+// Reads a .nii or .nii.gz file, compression is detected from the content
+pub fn read_nifti(path: &Path) -> io::Result<NiftiVolume> {
+    let bytes = fs::read(path)?;
+    if bytes.starts_with(&GZIP_MAGIC) {
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(bytes.as_slice()).read_to_end(&mut decompressed)?;
+        parse_nifti(&decompressed)
+    } else {
+        parse_nifti(&bytes)
+    }
+}
+
+// Loads an image and its segmentation, the two must share a grid
+pub fn load_anatomy(
+    image: &Path,
+    segmentation: &Path,
+    labels: &[LabelSpec],
+) -> io::Result<Anatomy> {
+    let image = read_nifti(image)?;
+    let segmentation = read_nifti(segmentation)?;
+    if !image.patient_box.same_grid(&segmentation.patient_box) {
+        return Err(invalid_data(
+            "image and segmentation differ in size, spacing or origin",
+        ));
+    }
+    Ok(Anatomy {
+        masks: segmentation.masks(labels),
+        patient_box: image.patient_box,
+        hounsfield: image.data,
+        study_uid: None,
+        frame_of_reference_uid: None,
+    })
+}
+
+// Serialises a float32 grid as a single-file NIfTI-1 image with an
+// axis-aligned scanner transform. Dimensions are 16 bit header fields,
+// larger grids are an error.
+pub fn encode_nifti(p_box: &PatientBox, data: &[f32], description: &str) -> io::Result<Vec<u8>> {
+    let dims = [3, p_box.x_size, p_box.y_size, p_box.z_size, 1, 1, 1, 1];
+    if let Some(dim) = dims[1..4]
+        .iter()
+        .find(|dim| !(1..=i16::MAX as i64).contains(*dim))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("grid dimension {} doesn't fit a NIfTI-1 header", dim),
+        ));
+    }
+    let mut bytes = vec![0u8; HEADER_SIZE as usize + 4];
+    let mut put = |offset: usize, field: &[u8]| {
+        bytes[offset..offset + field.len()].copy_from_slice(field);
+    };
+    put(0, &HEADER_SIZE.to_le_bytes());
+    for (axis, dim) in dims.iter().enumerate() {
+        put(40 + 2 * axis, &(*dim as i16).to_le_bytes());
+    }
+    put(70, &DT_FLOAT32.to_le_bytes());
+    put(72, &32i16.to_le_bytes());
+    let spacing = [1.0, p_box.spacing.x, p_box.spacing.y, p_box.spacing.z];
+    for (axis, pixdim) in spacing.iter().enumerate() {
+        put(76 + 4 * axis, &pixdim.to_le_bytes());
+    }
+    put(108, &(HEADER_SIZE as f32 + 4.0).to_le_bytes());
+    put(112, &1.0f32.to_le_bytes());
+    // Spatial units in mm
+    put(123, &[2]);
+    let description = description.as_bytes();
+    put(148, &description[..description.len().min(79)]);
+    put(252, &1i16.to_le_bytes());
+    put(254, &1i16.to_le_bytes());
+    let origin = [p_box.origin.x, p_box.origin.y, p_box.origin.z];
+    for (axis, offset) in origin.iter().enumerate() {
+        put(268 + 4 * axis, &offset.to_le_bytes());
+        let mut row = [0.0f32; 4];
+        row[axis] = spacing[axis + 1];
+        row[3] = *offset;
+        for (col, value) in row.iter().enumerate() {
+            put(280 + 16 * axis + 4 * col, &value.to_le_bytes());
+        }
+    }
+    put(344, b"n+1\0");
+    for value in data {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    Ok(bytes)
+}
+
+// Writes a grid as .nii, or gzip compressed when the path ends in .gz
+pub fn write_nifti(
+    path: &Path,
+    p_box: &PatientBox,
+    data: &[f32],
+    description: &str,
+) -> io::Result<()> {
+    let bytes = encode_nifti(p_box, data, description)?;
+    if path.extension().is_some_and(|ext| ext == "gz") {
+        let mut encoder = GzEncoder::new(fs::File::create(path)?, Compression::default());
+        encoder.write_all(&bytes)?;
+        encoder.finish()?;
+        Ok(())
+    } else {
+        fs::write(path, bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beam_utils::to_index;
+
+    // 4x3x2 int16 image with 2mm x 1mm x 3mm voxels and an sform offset
+    fn test_image(labels: &[i16]) -> Vec<u8> {
+        let mut bytes = vec![0u8; 352];
+        bytes[0..4].copy_from_slice(&HEADER_SIZE.to_le_bytes());
+        for (i, dim) in [3i16, 4, 3, 2, 1, 1, 1, 1].iter().enumerate() {
+            bytes[40 + 2 * i..42 + 2 * i].copy_from_slice(&dim.to_le_bytes());
+        }
+        bytes[70..72].copy_from_slice(&DT_INT16.to_le_bytes());
+        bytes[72..74].copy_from_slice(&16i16.to_le_bytes());
+        for (i, pixdim) in [1.0f32, 2.0, 1.0, 3.0].iter().enumerate() {
+            bytes[76 + 4 * i..80 + 4 * i].copy_from_slice(&pixdim.to_le_bytes());
+        }
+        bytes[108..112].copy_from_slice(&352.0f32.to_le_bytes());
+        bytes[254..256].copy_from_slice(&1i16.to_le_bytes());
+        for (offset, value) in [(292, -10.0f32), (308, 5.0), (324, 20.0)] {
+            bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        bytes[344..348].copy_from_slice(b"n+1\0");
+        for label in labels {
+            bytes.extend_from_slice(&label.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_read_nifti_labels() {
+        let mut labels = vec![0i16; 24];
+        labels[to_index(1, 1, 0, 4, 3)] = 1;
+        labels[to_index(2, 1, 1, 4, 3)] = 1;
+        labels[to_index(3, 2, 1, 4, 3)] = 2;
+
+        let path = std::env::temp_dir().join(format!("nifti_test_{}.nii.gz", std::process::id()));
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&test_image(&labels)).unwrap();
+        fs::write(&path, encoder.finish().unwrap()).unwrap();
+        let volume = read_nifti(&path);
+        fs::remove_file(&path).unwrap();
+        let volume = volume.unwrap();
+
+        let p_box = &volume.patient_box;
+        assert_eq!((p_box.x_size, p_box.y_size, p_box.z_size), (4, 3, 2));
+        assert_eq!(
+            (p_box.spacing.x, p_box.spacing.y, p_box.spacing.z),
+            (2.0, 1.0, 3.0)
+        );
+        assert_eq!(
+            (p_box.origin.x, p_box.origin.y, p_box.origin.z),
+            (-10.0, 5.0, 20.0)
+        );
+
+        let anatomy = Anatomy {
+            masks: volume.masks(&[
+                LabelSpec {
+                    label: 1,
+                    t_type: TissueType::Tumour,
+                    prescription: Some(40.0),
+                },
+                LabelSpec {
+                    label: 2,
+                    t_type: TissueType::SerialOrgan,
+                    prescription: None,
+                },
+            ]),
+            patient_box: volume.patient_box,
+            hounsfield: volume.data,
+            study_uid: None,
+            frame_of_reference_uid: None,
+        };
+        assert_eq!(anatomy.masks[0].count(), 2);
+        assert!(anatomy.masks[1].contains(3, 2, 1));
+
+        let targets = anatomy.targets();
+        assert_eq!(targets.len(), 1);
+        let tissue = &targets[0].tissue;
+        assert_eq!((tissue.x, tissue.y, tissue.z), (3, 1, 2));
+        assert_eq!((tissue.x_width, tissue.y_width, tissue.z_width), (2, 0, 3));
+
+        // A data offset past any file size is an error, not an overflow
+        for vox_offset in [f32::NAN, 1e30] {
+            let mut bytes = test_image(&labels);
+            bytes[108..112].copy_from_slice(&vox_offset.to_le_bytes());
+            assert!(parse_nifti(&bytes).is_err());
+        }
+    }
+
+    #[test]
+    fn test_anatomy_grid_mismatch() {
+        let dir = std::env::temp_dir();
+        let image = dir.join(format!("anatomy_image_{}.nii", std::process::id()));
+        let segmentation = dir.join(format!("anatomy_labels_{}.nii", std::process::id()));
+        fs::write(&image, test_image(&[0; 24])).unwrap();
+        let grid = PatientBox::new(4, 3, 2)
+            .with_spacing(Vector::new(2.0, 1.0, 3.0))
+            .with_origin(Vector::new(-10.0, 5.0, 20.0));
+        let labels = [LabelSpec {
+            label: 1,
+            t_type: TissueType::Tumour,
+            prescription: Some(40.0),
+        }];
+
+        fs::write(
+            &segmentation,
+            encode_nifti(&grid, &[1.0; 24], "labels").unwrap(),
+        )
+        .unwrap();
+        let same = load_anatomy(&image, &segmentation, &labels);
+        // Same voxel count but shifted by half a slice
+        let shifted = grid.clone().with_origin(Vector::new(-10.0, 5.0, 21.5));
+        fs::write(
+            &segmentation,
+            encode_nifti(&shifted, &[1.0; 24], "labels").unwrap(),
+        )
+        .unwrap();
+        let moved = load_anatomy(&image, &segmentation, &labels);
+        fs::remove_file(&image).unwrap();
+        fs::remove_file(&segmentation).unwrap();
+        assert_eq!(same.unwrap().masks[0].count(), 24);
+        assert!(moved.is_err());
+
+        // Dimensions past the 16 bit header fields are refused, not wrapped
+        let wide = PatientBox::new(40_000, 1, 1);
+        assert!(encode_nifti(&wide, &vec![0.0; 40_000], "dose").is_err());
+    }
+}
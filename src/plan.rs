@@ -0,0 +1,265 @@
+use crate::beam_utils::{Beam, Objectives, PatientBox, Target};
+use crate::dvh::Dvh;
+use crate::ga::{GaConfig, Indv};
+use crate::labels::{LabelVolume, OverlapPriority};
+use crate::mask::VoxelMask;
+use crate::nifti::invalid_data;
+use crate::quality::{PlanIndices, target_indices};
+use crate::vector::Vector;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Everything needed to rebuild and re-score an optimised plan. Structures
+// are stored voxelised so the plan doesn't depend on where they came from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Plan {
+    pub patient: PatientBox,
+    pub targets: Vec<Target>,
+    pub structures: Vec<VoxelMask>,
+    #[serde(default)]
+    pub structure_names: Vec<String>,
+    #[serde(default)]
+    pub body: Option<VoxelMask>,
+    #[serde(default)]
+    pub overlap_priority: OverlapPriority,
+    // Per-voxel attenuation per mm, homogeneous water when absent
+    #[serde(default)]
+    pub attenuation: Option<Vec<f32>>,
+    #[serde(default)]
+    pub objectives: Objectives,
+    pub beams: Vec<Beam>,
+    pub config: GaConfig,
+    pub fitness: f32,
+    // Best fitness of each generation of the run that produced the plan
+    #[serde(default)]
+    pub convergence: Vec<f32>,
+}
+
+impl Plan {
+    // A plan file is checked once on load so scoring it can index the
+    // targets, masks and attenuation map without going out of bounds
+    pub fn validate(&self) -> io::Result<()> {
+        self.patient.validate()?;
+        for beam in &self.beams {
+            let (target, arc) = match beam {
+                Beam::Static { target, .. } => (*target, None),
+                Beam::Arc { arc, target } => (*target, Some(arc)),
+            };
+            if target >= self.targets.len() {
+                return Err(invalid_data(format!(
+                    "beam aims at target {} of {}",
+                    target,
+                    self.targets.len()
+                )));
+            }
+            if let Some(arc) = arc {
+                arc.validate()?;
+            }
+        }
+        let masks = self.structures.iter().chain(self.body.as_ref());
+        if masks
+            .into_iter()
+            .any(|mask| !mask.patient_box.same_grid(&self.patient))
+        {
+            return Err(invalid_data("plan structure is on a different grid"));
+        }
+        if let Some(attenuation) = &self.attenuation
+            && attenuation.len() as i64 != self.patient.grid_size()
+        {
+            return Err(invalid_data(format!(
+                "attenuation map has {} voxels, the grid has {}",
+                attenuation.len(),
+                self.patient.grid_size()
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn labels(&self) -> io::Result<LabelVolume> {
+        let mut labels =
+            LabelVolume::from_structures(&self.structures, &self.patient, &self.overlap_priority)?;
+        if let Some(body) = &self.body {
+            labels = labels.with_body(body.clone());
+        }
+        if let Some(attenuation) = &self.attenuation {
+            labels = labels.with_attenuation(attenuation.clone());
+        }
+        Ok(labels)
+    }
+
+    pub fn structure_name(&self, structure_idx: usize) -> String {
+        match self.structure_names.get(structure_idx) {
+            Some(name) => name.clone(),
+            None => format!("Structure {}", structure_idx),
+        }
+    }
+
+    pub fn indv(&self) -> Indv {
+        Indv {
+            beams: self.beams.clone(),
+            fitness: self.fitness,
+        }
+    }
+
+    // Scores the stored beams the same way `ga` does, a plan saved from
+    // an optimisation run gives back its recorded fitness exactly
+    pub fn evaluate(&self) -> io::Result<f32> {
+        let mut indv = self.indv();
+        indv.calculate_fitness(
+            &self.patient,
+            &self.targets,
+            &self.labels()?,
+            &self.objectives,
+        );
+        Ok(indv.fitness)
+    }
+
+    // Point slices are drawn through by default, the centre of the
+    // first target or of the grid when there are no targets
+    pub fn view_centre(&self) -> Vector {
+        match self.targets.first() {
+            Some(target) => target.tissue.centre(),
+            None => self.patient.extent().mult_vec(0.5),
+        }
+    }
+
+    pub fn dose(&self) -> io::Result<Vec<f32>> {
+        Ok(self
+            .indv()
+            .compute_dose(&self.patient, &self.targets, &self.labels()?)
+            .dose_matrix)
+    }
+
+    // Quality indices of every target, named like the structures
+    pub fn indices(&self, dose: &[f32]) -> io::Result<Vec<(String, PlanIndices)>> {
+        Ok(
+            target_indices(dose, &self.labels()?, self.objectives.default_prescription)
+                .into_iter()
+                .map(|(structure_idx, indices)| (self.structure_name(structure_idx), indices))
+                .collect(),
+        )
+    }
+
+    pub fn dvhs(&self, dose: &[f32]) -> Vec<Dvh> {
+        self.structures
+            .iter()
+            .enumerate()
+            .map(|(idx, mask)| Dvh::from_mask(&self.structure_name(idx), mask, dose))
+            .collect()
+    }
+}
+
+pub fn save_plan(path: &Path, plan: &Plan) -> io::Result<()> {
+    fs::write(path, serde_json::to_string_pretty(plan)?)
+}
+
+pub fn load_plan(path: &Path) -> io::Result<Plan> {
+    let plan: Plan = serde_json::from_str(&fs::read_to_string(path)?)?;
+    plan.validate()?;
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arc::GantryArc;
+    use crate::beam_utils::{TissueBox, TissueType};
+    use crate::collision::CollisionModel;
+    use crate::ga::DeliveryMode;
+    use crate::primitives::{Sphere, voxelise};
+
+    #[test]
+    fn test_plan_round_trip() {
+        let patient = PatientBox::new(24, 24, 12).with_spacing(Vector::new(1.0, 1.0, 2.0));
+        let targets = vec![Target {
+            tissue: TissueBox {
+                x: 12,
+                y: 12,
+                z: 12,
+                x_width: 4,
+                y_width: 4,
+                z_width: 4,
+                tissue_type: Some(TissueType::Tumour),
+            },
+            prescription: 30.0,
+        }];
+        let mut tumour = voxelise(
+            &Sphere {
+                centre: Vector::new(12.0, 12.0, 12.0),
+                radius: 3.0,
+            },
+            &patient,
+            TissueType::Tumour,
+        );
+        tumour.prescription = Some(30.0);
+        let config = GaConfig {
+            population_size: 4,
+            generations: 1,
+            tournament_size: 2,
+            delivery: DeliveryMode::Mixed,
+        };
+        let mut indv = Indv {
+            beams: config
+                .delivery
+                .initial_beams(&patient, &targets, &CollisionModel::default()),
+            fitness: 0.0,
+        };
+        let mut plan = Plan {
+            patient,
+            targets,
+            structures: vec![tumour],
+            structure_names: vec!["PTV".to_string()],
+            body: None,
+            overlap_priority: OverlapPriority::TargetWins,
+            attenuation: None,
+            objectives: Objectives {
+                weight_healthy: 0.5,
+                ..Objectives::default()
+            },
+            beams: vec![],
+            config,
+            fitness: 0.0,
+            convergence: vec![],
+        };
+        indv.calculate_fitness(
+            &plan.patient,
+            &plan.targets,
+            &plan.labels().unwrap(),
+            &plan.objectives,
+        );
+        plan.beams = indv.beams;
+        plan.fitness = indv.fitness;
+
+        let path = std::env::temp_dir().join(format!("plan_{}.json", std::process::id()));
+        save_plan(&path, &plan).unwrap();
+        let loaded = load_plan(&path);
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+
+        assert_eq!(loaded.structures[0].count(), plan.structures[0].count());
+        assert_eq!(loaded.beams.len(), plan.beams.len());
+        assert_eq!(loaded.objectives, plan.objectives);
+        assert_eq!(loaded.evaluate().unwrap(), plan.fitness);
+        assert_eq!(loaded.dvhs(&loaded.dose().unwrap())[0].name, "PTV");
+
+        // Corrupted plans are refused on load rather than panicking later
+        let mut bad_target = plan.clone();
+        bad_target.targets.clear();
+        let mut bad_attenuation = plan.clone();
+        bad_attenuation.attenuation = Some(vec![0.02; 10]);
+        let mut bad_grid = plan.clone();
+        bad_grid.structures[0] = VoxelMask::new(&PatientBox::new(8, 8, 8), TissueType::Tumour);
+        let mut bad_arc = plan.clone();
+        let mut arc = GantryArc::new(0.0, 90.0, 10.0, 0.0).unwrap();
+        arc.spacing = 0.0;
+        bad_arc.beams.push(Beam::Arc { arc, target: 0 });
+        for corrupted in [bad_target, bad_attenuation, bad_grid, bad_arc] {
+            save_plan(&path, &corrupted).unwrap();
+            let loaded = load_plan(&path);
+            fs::remove_file(&path).unwrap();
+            assert_eq!(loaded.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
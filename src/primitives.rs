@@ -0,0 +1,186 @@
+use crate::beam_utils::{PatientBox, TissueBox, TissueType};
+use crate::mask::VoxelMask;
+use crate::vector::Vector;
+use serde::{Deserialize, Serialize};
+
+// Analytic shape in mm from the grid corner, voxels are sampled at
+// their grid positions
+pub trait Solid: Send + Sync {
+    fn contains_point(&self, point: &Vector) -> bool;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sphere {
+    pub centre: Vector,
+    pub radius: f32,
+}
+
+impl Solid for Sphere {
+    fn contains_point(&self, point: &Vector) -> bool {
+        self.centre.dist_to_vector(point) <= self.radius
+    }
+}
+
+// Semi-axes are given in the ellipsoid's own frame, which is rotated
+// by `rotation` degrees about x, then y, then z
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ellipsoid {
+    pub centre: Vector,
+    pub radii: Vector,
+    pub rotation: Vector,
+}
+
+impl Solid for Ellipsoid {
+    fn contains_point(&self, point: &Vector) -> bool {
+        let mut offset = *point;
+        offset.calculate_offset(&self.centre);
+        let local = inverse_rotate(&offset, &self.rotation);
+        (local.x / self.radii.x).powf(2.0)
+            + (local.y / self.radii.y).powf(2.0)
+            + (local.z / self.radii.z).powf(2.0)
+            <= 1.0
+    }
+}
+
+// Finite cylinder between the centres of its two end caps
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cylinder {
+    pub start: Vector,
+    pub end: Vector,
+    pub radius: f32,
+}
+
+impl Solid for Cylinder {
+    fn contains_point(&self, point: &Vector) -> bool {
+        let mut axis = self.end;
+        axis.calculate_offset(&self.start);
+        let mut offset = *point;
+        offset.calculate_offset(&self.start);
+        let t = offset.dot(&axis) / axis.dot(&axis);
+        if !(0.0..=1.0).contains(&t) {
+            return false;
+        }
+        offset.dist_to_vector(&axis.mult_vec(t)) <= self.radius
+    }
+}
+
+impl Solid for TissueBox {
+    fn contains_point(&self, point: &Vector) -> bool {
+        let inside = |p: f32, c: i64, w: i64| p >= (c - w / 2) as f32 && p <= (c + w / 2) as f32;
+        inside(point.x, self.x, self.x_width)
+            && inside(point.y, self.y, self.y_width)
+            && inside(point.z, self.z, self.z_width)
+    }
+}
+
+// Constructive solid geometry on top of the primitives
+pub enum Csg {
+    Union(Vec<Box<dyn Solid>>),
+    Intersection(Vec<Box<dyn Solid>>),
+    Difference(Box<dyn Solid>, Box<dyn Solid>),
+}
+
+impl Solid for Csg {
+    fn contains_point(&self, point: &Vector) -> bool {
+        match self {
+            Csg::Union(solids) => solids.iter().any(|solid| solid.contains_point(point)),
+            Csg::Intersection(solids) => solids.iter().all(|solid| solid.contains_point(point)),
+            Csg::Difference(base, cut) => base.contains_point(point) && !cut.contains_point(point),
+        }
+    }
+}
+
+pub fn voxelise<S: Solid + ?Sized>(solid: &S, p_box: &PatientBox, t_type: TissueType) -> VoxelMask {
+    let mut mask = VoxelMask::new(p_box, t_type);
+    for x in 0..p_box.x_size {
+        for y in 0..p_box.y_size {
+            for z in 0..p_box.z_size {
+                if solid.contains_point(&p_box.voxel_position(x, y, z)) {
+                    mask.set(x, y, z, true);
+                }
+            }
+        }
+    }
+    mask
+}
+
+fn inverse_rotate(v: &Vector, rotation: &Vector) -> Vector {
+    let (sx, cx) = rotation.x.to_radians().sin_cos();
+    let (sy, cy) = rotation.y.to_radians().sin_cos();
+    let (sz, cz) = rotation.z.to_radians().sin_cos();
+    // Undo z, then y, then x
+    let v1 = Vector::new(cz * v.x + sz * v.y, -sz * v.x + cz * v.y, v.z);
+    let v2 = Vector::new(cy * v1.x - sy * v1.z, v1.y, sy * v1.x + cy * v1.z);
+    Vector::new(v2.x, cx * v2.y + sx * v2.z, -sx * v2.y + cx * v2.z)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATIENT: PatientBox = PatientBox::new(30, 30, 30);
+
+    #[test]
+    fn test_primitive_voxelisation() {
+        let sphere = Sphere {
+            centre: Vector::new(15.0, 15.0, 15.0),
+            radius: 5.0,
+        };
+        let volume = voxelise(&sphere, &PATIENT, TissueType::Tumour).count() as f32;
+        let expected = 4.0 / 3.0 * std::f32::consts::PI * 125.0;
+        assert!((volume - expected).abs() / expected < 0.05);
+
+        // A long thin ellipsoid rotated onto the y axis
+        let ellipsoid = Ellipsoid {
+            centre: Vector::new(15.0, 15.0, 15.0),
+            radii: Vector::new(10.0, 2.0, 2.0),
+            rotation: Vector::new(0.0, 0.0, 90.0),
+        };
+        assert!(ellipsoid.contains_point(&Vector::new(15.0, 24.0, 15.0)));
+        assert!(!ellipsoid.contains_point(&Vector::new(24.0, 15.0, 15.0)));
+
+        let cylinder = Cylinder {
+            start: Vector::new(15.0, 0.0, 15.0),
+            end: Vector::new(15.0, 29.0, 15.0),
+            radius: 3.0,
+        };
+        assert!(cylinder.contains_point(&Vector::new(17.0, 5.0, 15.0)));
+        assert!(!cylinder.contains_point(&Vector::new(19.0, 5.0, 15.0)));
+    }
+
+    #[test]
+    fn test_csg_phantom() {
+        let cord = Cylinder {
+            start: Vector::new(15.0, 0.0, 10.0),
+            end: Vector::new(15.0, 29.0, 10.0),
+            radius: 2.0,
+        };
+        let tumour = Sphere {
+            centre: Vector::new(15.0, 15.0, 12.0),
+            radius: 4.0,
+        };
+        let cord_count = voxelise(&cord, &PATIENT, TissueType::SerialOrgan).count();
+        let tumour_count = voxelise(&tumour, &PATIENT, TissueType::Tumour).count();
+        let overlap = voxelise(
+            &Csg::Intersection(vec![Box::new(cord.clone()), Box::new(tumour.clone())]),
+            &PATIENT,
+            TissueType::SerialOrgan,
+        )
+        .count();
+        assert!(overlap > 0);
+        let union = voxelise(
+            &Csg::Union(vec![Box::new(cord.clone()), Box::new(tumour.clone())]),
+            &PATIENT,
+            TissueType::Tumour,
+        )
+        .count();
+        assert_eq!(union, cord_count + tumour_count - overlap);
+        let tumour_minus_cord = voxelise(
+            &Csg::Difference(Box::new(tumour), Box::new(cord)),
+            &PATIENT,
+            TissueType::Tumour,
+        )
+        .count();
+        assert_eq!(tumour_minus_cord, tumour_count - overlap);
+    }
+}
@@ -0,0 +1,161 @@
+use crate::beam_utils::{Objectives, TissueType};
+use crate::dvh::Dvh;
+use crate::labels::LabelVolume;
+
+// Share of the prescription defining the low dose volume of the
+// gradient index
+const GRADIENT_LEVEL: f32 = 0.5;
+
+// Plan quality indices of one target. Volumes are voxel counts inside
+// the body, only their ratios are used.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlanIndices {
+    // Prescription isodose volume over target volume, ideally 1
+    pub rtog_ci: f32,
+    // Covered target volume squared over target times prescription
+    // isodose volume, ideally 1
+    pub paddick_ci: f32,
+    // (D2 - D98) / D50 of the target, ideally 0
+    pub homogeneity: f32,
+    // Half prescription isodose volume over prescription isodose volume
+    pub gradient: f32,
+}
+
+impl PlanIndices {
+    // Zero for an ideal plan, grows as the plan gets worse
+    pub fn cost(&self, objectives: &Objectives) -> f32 {
+        objectives.weight_rtog_ci * (self.rtog_ci - 1.0).abs()
+            + objectives.weight_paddick_ci * (1.0 - self.paddick_ci)
+            + objectives.weight_homogeneity * self.homogeneity
+            + objectives.weight_gradient * (self.gradient - 1.0).max(0.0)
+    }
+}
+
+// Indices of the target `structure_idx`. Without any voxel at the
+// prescription the gradient index divides by a single voxel so it
+// stays finite.
+pub fn plan_indices(
+    dose: &[f32],
+    labels: &LabelVolume,
+    structure_idx: usize,
+    prescription: f32,
+) -> PlanIndices {
+    let mut target_doses = Vec::new();
+    let (mut prescription_volume, mut covered_target, mut gradient_volume) =
+        (0usize, 0usize, 0usize);
+    for (index, voxel_dose) in dose.iter().enumerate() {
+        if !labels.in_body(index) {
+            continue;
+        }
+        let in_target = labels.in_structure(index, structure_idx);
+        if in_target {
+            target_doses.push(*voxel_dose);
+        }
+        if *voxel_dose >= prescription {
+            prescription_volume += 1;
+            covered_target += in_target as usize;
+        }
+        if *voxel_dose >= prescription * GRADIENT_LEVEL {
+            gradient_volume += 1;
+        }
+    }
+    target_doses.sort_by(|a, b| a.total_cmp(b));
+    let target = Dvh {
+        name: String::new(),
+        t_type: TissueType::Tumour,
+        prescription: Some(prescription),
+        doses: target_doses,
+    };
+
+    let target_volume = target.doses.len() as f32;
+    let median = target.dose_at_volume(0.5);
+    PlanIndices {
+        rtog_ci: prescription_volume as f32 / target_volume.max(1.0),
+        paddick_ci: if prescription_volume == 0 || target_volume == 0.0 {
+            0.0
+        } else {
+            (covered_target as f32).powi(2) / (target_volume * prescription_volume as f32)
+        },
+        homogeneity: if median > 0.0 {
+            (target.dose_at_volume(0.02) - target.dose_at_volume(0.98)) / median
+        } else {
+            0.0
+        },
+        gradient: gradient_volume as f32 / prescription_volume.max(1) as f32,
+    }
+}
+
+// Indices of every tumour structure with its own prescription, or the
+// default one
+pub fn target_indices(
+    dose: &[f32],
+    labels: &LabelVolume,
+    default_prescription: f32,
+) -> Vec<(usize, PlanIndices)> {
+    labels
+        .tissue_types
+        .iter()
+        .enumerate()
+        .filter(|(_, t_type)| matches!(t_type, TissueType::Tumour))
+        .map(|(structure_idx, _)| {
+            let prescription = labels.prescriptions[structure_idx].unwrap_or(default_prescription);
+            (
+                structure_idx,
+                plan_indices(dose, labels, structure_idx, prescription),
+            )
+        })
+        .collect()
+}
+
+// Optional objective terms, skipped entirely while all index weights
+// are zero so the default cost is unchanged
+pub fn compute_index_cost(dose: &[f32], labels: &LabelVolume, objectives: &Objectives) -> f32 {
+    if !objectives.uses_indices() {
+        return 0.0;
+    }
+    target_indices(dose, labels, objectives.default_prescription)
+        .iter()
+        .map(|(_, indices)| indices.cost(objectives))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beam_utils::PatientBox;
+    use crate::labels::OverlapPriority;
+    use crate::mask::VoxelMask;
+
+    #[test]
+    fn test_plan_indices() {
+        let patient = PatientBox::new(10, 1, 1);
+        let mut tumour = VoxelMask::new(&patient, TissueType::Tumour);
+        for x in 2..6 {
+            tumour.set(x, 0, 0, true);
+        }
+        tumour.prescription = Some(40.0);
+        let labels =
+            LabelVolume::from_structures(&[tumour], &patient, &OverlapPriority::CountAll).unwrap();
+        // Target at x 2..6, prescription dose covers x 3..7
+        let dose = [0.0, 10.0, 30.0, 40.0, 44.0, 42.0, 40.0, 25.0, 20.0, 0.0];
+
+        let indices = target_indices(&dose, &labels, 60.0);
+        assert_eq!(indices.len(), 1);
+        let indices = &indices[0].1;
+        assert_eq!(indices.rtog_ci, 1.0);
+        assert_eq!(indices.paddick_ci, 9.0 / 16.0);
+        assert_eq!(indices.homogeneity, (44.0 - 30.0) / 42.0);
+        assert_eq!(indices.gradient, 7.0 / 4.0);
+
+        let objectives = Objectives {
+            weight_paddick_ci: 2.0,
+            weight_gradient: 1.0,
+            ..Objectives::default()
+        };
+        assert_eq!(indices.cost(&objectives), 2.0 * 7.0 / 16.0 + 0.75);
+        assert_eq!(
+            compute_index_cost(&dose, &labels, &Objectives::default()),
+            0.0
+        );
+    }
+}
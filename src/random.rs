@@ -0,0 +1,49 @@
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use std::cell::RefCell;
+
+thread_local! {
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::from_os_rng());
+}
+
+// Handle to the per-thread generator used by the optimiser. Population
+// building, selection, crossover and mutation all run on the calling
+// thread, so seeding it makes a run repeatable.
+pub struct OptimiserRng;
+
+impl RngCore for OptimiserRng {
+    fn next_u32(&mut self) -> u32 {
+        RNG.with(|rng| rng.borrow_mut().next_u32())
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        RNG.with(|rng| rng.borrow_mut().next_u64())
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        RNG.with(|rng| rng.borrow_mut().fill_bytes(dest))
+    }
+}
+
+pub fn rng() -> OptimiserRng {
+    OptimiserRng
+}
+
+pub fn seed(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(seed));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_seeded_rng_repeats() {
+        seed(7);
+        let first: Vec<f32> = (0..5).map(|_| rng().random_range(0.0..1.0)).collect();
+        seed(7);
+        let second: Vec<f32> = (0..5).map(|_| rng().random_range(0.0..1.0)).collect();
+        assert_eq!(first, second);
+    }
+}
@@ -0,0 +1,316 @@
+use crate::beam_utils::{Objectives, PatientBox, TissueType, to_index};
+use crate::mask::VoxelMask;
+use crate::vector::Vector;
+use flate2::Compression;
+use flate2::Crc;
+use flate2::write::ZlibEncoder;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use strum_macros::{Display, EnumIter, EnumString};
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+// Dose wash is dimmed so outlines and isodose lines stand out
+const WASH_BRIGHTNESS: f32 = 0.6;
+// Doses below this fraction of the reference are left black
+const WASH_CUTOFF: f32 = 0.01;
+
+// Axial slices are at fixed z, sagittal at fixed x and coronal at fixed y
+#[derive(Debug, Clone, Copy, PartialEq, EnumString, EnumIter, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum SliceAxis {
+    Axial,
+    Sagittal,
+    Coronal,
+}
+
+impl SliceAxis {
+    // Voxel count along the axis the slice is taken across
+    pub fn depth(&self, p_box: &PatientBox) -> i64 {
+        match self {
+            SliceAxis::Axial => p_box.z_size,
+            SliceAxis::Sagittal => p_box.x_size,
+            SliceAxis::Coronal => p_box.y_size,
+        }
+    }
+
+    // Slice containing a point given in mm from the grid corner
+    pub fn slice_through(&self, p_box: &PatientBox, point: &Vector) -> i64 {
+        match self {
+            SliceAxis::Axial => (point.z / p_box.spacing.z).round() as i64,
+            SliceAxis::Sagittal => (point.x / p_box.spacing.x).round() as i64,
+            SliceAxis::Coronal => (point.y / p_box.spacing.y).round() as i64,
+        }
+    }
+
+    // (size, spacing) of the image columns and rows
+    fn image_axes(&self, p_box: &PatientBox) -> ((i64, f32), (i64, f32)) {
+        let (x, y, z) = (
+            (p_box.x_size, p_box.spacing.x),
+            (p_box.y_size, p_box.spacing.y),
+            (p_box.z_size, p_box.spacing.z),
+        );
+        match self {
+            SliceAxis::Axial => (x, y),
+            SliceAxis::Sagittal => (y, z),
+            SliceAxis::Coronal => (x, z),
+        }
+    }
+
+    // Voxel under image column `u` and row `v`, rows of sagittal and
+    // coronal slices run from high z at the top
+    fn voxel(&self, u: i64, v: i64, slice: i64, p_box: &PatientBox) -> (i64, i64, i64) {
+        match self {
+            SliceAxis::Axial => (u, v, slice),
+            SliceAxis::Sagittal => (slice, u, p_box.z_size - 1 - v),
+            SliceAxis::Coronal => (u, slice, p_box.z_size - 1 - v),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    pub pixels_per_mm: f32,
+    // Dose the colour map and isodose percentages are relative to
+    pub reference_dose: f32,
+    pub isodose_percentages: Vec<f32>,
+}
+
+impl Default for RenderOptions {
+    fn default() -> RenderOptions {
+        RenderOptions {
+            pixels_per_mm: 2.0,
+            reference_dose: Objectives::default().default_prescription,
+            isodose_percentages: vec![50.0, 80.0, 95.0, 107.0],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RgbImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<[u8; 3]>,
+}
+
+impl RgbImage {
+    pub fn encode_ppm(&self) -> Vec<u8> {
+        let mut bytes = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        bytes.extend(self.pixels.iter().flatten());
+        bytes
+    }
+
+    pub fn encode_png(&self) -> io::Result<Vec<u8>> {
+        let mut header = Vec::new();
+        header.extend((self.width as u32).to_be_bytes());
+        header.extend((self.height as u32).to_be_bytes());
+        // 8 bit truecolour, deflate, no filtering, not interlaced
+        header.extend([8, 2, 0, 0, 0]);
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        for row in self.pixels.chunks(self.width) {
+            encoder.write_all(&[0])?;
+            encoder.write_all(row.as_flattened())?;
+        }
+
+        let mut bytes = PNG_SIGNATURE.to_vec();
+        png_chunk(&mut bytes, b"IHDR", &header);
+        png_chunk(&mut bytes, b"IDAT", &encoder.finish()?);
+        png_chunk(&mut bytes, b"IEND", &[]);
+        Ok(bytes)
+    }
+
+    // PPM for a .ppm extension, PNG otherwise
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let is_ppm = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("ppm"));
+        if is_ppm {
+            fs::write(path, self.encode_ppm())
+        } else {
+            fs::write(path, self.encode_png()?)
+        }
+    }
+}
+
+fn png_chunk(bytes: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    bytes.extend((data.len() as u32).to_be_bytes());
+    bytes.extend(kind);
+    bytes.extend(data);
+    bytes.extend(crc.sum().to_be_bytes());
+}
+
+// Jet colour map, blue at 0 through green to red at 1
+pub fn jet(t: f32) -> [u8; 3] {
+    let t = t.clamp(0.0, 1.0);
+    let channel = |offset: f32| ((1.5 - (4.0 * t - offset).abs()).clamp(0.0, 1.0) * 255.0) as u8;
+    [channel(3.0), channel(2.0), channel(1.0)]
+}
+
+fn outline_colour(t_type: &TissueType) -> [u8; 3] {
+    match t_type {
+        TissueType::Tumour => [255, 0, 255],
+        TissueType::SerialOrgan => [255, 255, 255],
+        TissueType::ParallelOrgan => [160, 160, 160],
+    }
+}
+
+// Draws one slice of the dose grid with the colour wash, isodose lines
+// at each percentage of the reference dose and structure outlines on top
+pub fn render_slice(
+    p_box: &PatientBox,
+    dose: &[f32],
+    masks: &[VoxelMask],
+    axis: SliceAxis,
+    slice: i64,
+    options: &RenderOptions,
+) -> RgbImage {
+    let ((u_size, u_spacing), (v_size, v_spacing)) = axis.image_axes(p_box);
+    let width = ((u_size as f32 * u_spacing * options.pixels_per_mm).round() as usize).max(1);
+    let height = ((v_size as f32 * v_spacing * options.pixels_per_mm).round() as usize).max(1);
+    let slice = slice.clamp(0, axis.depth(p_box) - 1);
+
+    // Voxel under each pixel, nearest neighbour in mm
+    let voxels: Vec<(i64, i64, i64)> = (0..width * height)
+        .map(|pixel| {
+            let u_mm = ((pixel % width) as f32 + 0.5) / options.pixels_per_mm;
+            let v_mm = ((pixel / width) as f32 + 0.5) / options.pixels_per_mm;
+            let u = ((u_mm / u_spacing) as i64).min(u_size - 1);
+            let v = ((v_mm / v_spacing) as i64).min(v_size - 1);
+            axis.voxel(u, v, slice, p_box)
+        })
+        .collect();
+    let doses: Vec<f32> = voxels
+        .iter()
+        .map(|(x, y, z)| {
+            dose[to_index(
+                *x as usize,
+                *y as usize,
+                *z as usize,
+                p_box.x_size as usize,
+                p_box.y_size as usize,
+            )]
+        })
+        .collect();
+
+    // A pixel is on the edge of a region when it is inside and one of
+    // its four neighbours isn't
+    let on_edge = |inside: &dyn Fn(usize) -> bool, pixel: usize| {
+        let (col, row) = (pixel % width, pixel / width);
+        inside(pixel)
+            && ((col > 0 && !inside(pixel - 1))
+                || (col + 1 < width && !inside(pixel + 1))
+                || (row > 0 && !inside(pixel - width))
+                || (row + 1 < height && !inside(pixel + width)))
+    };
+
+    let top_dose = options.reference_dose * 1.1;
+    let mut pixels: Vec<[u8; 3]> = doses
+        .iter()
+        .map(|dose| {
+            if *dose < options.reference_dose * WASH_CUTOFF {
+                return [0, 0, 0];
+            }
+            jet(dose / top_dose).map(|channel| (channel as f32 * WASH_BRIGHTNESS) as u8)
+        })
+        .collect();
+
+    for percentage in &options.isodose_percentages {
+        let level = options.reference_dose * percentage / 100.0;
+        let colour = jet(level / top_dose);
+        let inside = |pixel: usize| doses[pixel] >= level;
+        for (pixel, value) in pixels.iter_mut().enumerate() {
+            if on_edge(&inside, pixel) {
+                *value = colour;
+            }
+        }
+    }
+
+    for mask in masks {
+        let colour = outline_colour(&mask.t_type);
+        let inside = |pixel: usize| {
+            let (x, y, z) = voxels[pixel];
+            mask.contains(x, y, z)
+        };
+        for (pixel, value) in pixels.iter_mut().enumerate() {
+            if on_edge(&inside, pixel) {
+                *value = colour;
+            }
+        }
+    }
+
+    RgbImage {
+        width,
+        height,
+        pixels,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::{Sphere, voxelise};
+
+    #[test]
+    fn test_render_slice() {
+        let patient = PatientBox::new(20, 20, 10).with_spacing(Vector::new(1.0, 1.0, 2.0));
+        let centre = Vector::new(10.0, 10.0, 10.0);
+        let dose: Vec<f32> = (0..patient.grid_size())
+            .map(|index| {
+                let (x, y, z) = crate::beam_utils::to_coords(index, 20, 20);
+                40.0 - patient.voxel_position(x, y, z).dist_to_vector(&centre)
+            })
+            .collect();
+        let tumour = voxelise(
+            &Sphere {
+                centre,
+                radius: 4.0,
+            },
+            &patient,
+            TissueType::Tumour,
+        );
+        let options = RenderOptions {
+            pixels_per_mm: 1.0,
+            ..RenderOptions::default()
+        };
+
+        let axial = render_slice(
+            &patient,
+            &dose,
+            std::slice::from_ref(&tumour),
+            SliceAxis::Axial,
+            5,
+            &options,
+        );
+        assert_eq!((axial.width, axial.height), (20, 20));
+        assert_eq!(
+            axial.pixels[10 * 20 + 6],
+            outline_colour(&TissueType::Tumour)
+        );
+        // Centre is at 100% of the reference, inside the 95% line
+        assert_eq!(
+            axial.pixels[10 * 20 + 10],
+            jet(40.0 / 44.0).map(|c| (c as f32 * WASH_BRIGHTNESS) as u8)
+        );
+        let sagittal = render_slice(
+            &patient,
+            &dose,
+            &[tumour],
+            SliceAxis::Sagittal,
+            10,
+            &options,
+        );
+        assert_eq!((sagittal.width, sagittal.height), (20, 20));
+
+        let png = axial.encode_png().unwrap();
+        assert_eq!(png[..8], PNG_SIGNATURE);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(
+            axial.encode_ppm().len(),
+            "P6\n20 20\n255\n".len() + 20 * 20 * 3
+        );
+    }
+}
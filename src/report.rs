@@ -0,0 +1,432 @@
+use crate::beam_utils::{compute_beam_count_cost, compute_cost_breakdown};
+use crate::dvh::Dvh;
+use crate::plan::Plan;
+use crate::quality::compute_index_cost;
+use crate::render::{RenderOptions, SliceAxis, render_slice};
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+use strum::IntoEnumIterator;
+
+const CHART_WIDTH: f32 = 640.0;
+const CHART_HEIGHT: f32 = 320.0;
+const CHART_MARGIN: f32 = 48.0;
+const CHART_TICKS: usize = 5;
+const SERIES_COLOURS: [&str; 8] = [
+    "#d62728", "#1f77b4", "#2ca02c", "#ff7f0e", "#9467bd", "#8c564b", "#e377c2", "#17becf",
+];
+const STYLE: &str = "body{font-family:sans-serif;margin:2em;color:#222}\
+table{border-collapse:collapse;margin:1em 0}\
+td,th{border:1px solid #ccc;padding:4px 8px;text-align:right}\
+th:first-child,td:first-child{text-align:left}\
+img{margin-right:1em;image-rendering:pixelated}";
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn base64(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let word = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        for sextet in 0..4 {
+            if sextet <= chunk.len() {
+                text.push(BASE64_ALPHABET[(word >> (18 - 6 * sextet) & 0x3f) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// SVG line chart with one polyline per named series, axes start at zero
+// unless a series goes negative
+fn line_chart(series: &[(String, Vec<(f32, f32)>)], x_label: &str, y_label: &str) -> String {
+    let points = series.iter().flat_map(|(_, points)| points);
+    let (mut x_min, mut x_max, mut y_min, mut y_max) = (0.0f32, 0.0f32, 0.0f32, 0.0f32);
+    for (x, y) in points {
+        (x_min, x_max) = (x_min.min(*x), x_max.max(*x));
+        (y_min, y_max) = (y_min.min(*y), y_max.max(*y));
+    }
+    if x_max <= x_min {
+        x_max = x_min + 1.0;
+    }
+    if y_max <= y_min {
+        y_max = y_min + 1.0;
+    }
+    let plot_width = CHART_WIDTH - 2.0 * CHART_MARGIN;
+    let plot_height = CHART_HEIGHT - 2.0 * CHART_MARGIN;
+    let to_x = |x: f32| CHART_MARGIN + (x - x_min) / (x_max - x_min) * plot_width;
+    let to_y = |y: f32| CHART_HEIGHT - CHART_MARGIN - (y - y_min) / (y_max - y_min) * plot_height;
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" font-size=\"11\">",
+        CHART_WIDTH, CHART_HEIGHT
+    );
+    let _ = write!(
+        svg,
+        "<rect x=\"{m}\" y=\"{m}\" width=\"{}\" height=\"{}\" fill=\"none\" stroke=\"#888\"/>",
+        plot_width,
+        plot_height,
+        m = CHART_MARGIN
+    );
+    for tick in 0..=CHART_TICKS {
+        let fraction = tick as f32 / CHART_TICKS as f32;
+        let (x, y) = (
+            x_min + fraction * (x_max - x_min),
+            y_min + fraction * (y_max - y_min),
+        );
+        let _ = write!(
+            svg,
+            "<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"middle\">{}</text>\
+             <text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"end\">{}</text>",
+            to_x(x),
+            CHART_HEIGHT - CHART_MARGIN + 14.0,
+            format_tick(x),
+            CHART_MARGIN - 4.0,
+            to_y(y) + 4.0,
+            format_tick(y)
+        );
+    }
+    let _ = write!(
+        svg,
+        "<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"middle\">{}</text>\
+         <text x=\"12\" y=\"{:.1}\" text-anchor=\"middle\" transform=\"rotate(-90 12 {:.1})\">{}</text>",
+        CHART_WIDTH / 2.0,
+        CHART_HEIGHT - 8.0,
+        escape(x_label),
+        CHART_HEIGHT / 2.0,
+        CHART_HEIGHT / 2.0,
+        escape(y_label)
+    );
+    for (idx, (name, points)) in series.iter().enumerate() {
+        let colour = SERIES_COLOURS[idx % SERIES_COLOURS.len()];
+        let path: Vec<String> = points
+            .iter()
+            .map(|(x, y)| format!("{:.1},{:.1}", to_x(*x), to_y(*y)))
+            .collect();
+        let _ = write!(
+            svg,
+            "<polyline fill=\"none\" stroke=\"{}\" stroke-width=\"1.5\" points=\"{}\"/>",
+            colour,
+            path.join(" ")
+        );
+        if series.len() > 1 {
+            let legend_y = CHART_MARGIN + 14.0 * idx as f32 + 12.0;
+            let _ = write!(
+                svg,
+                "<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"end\" fill=\"{}\">{}</text>",
+                CHART_WIDTH - CHART_MARGIN - 6.0,
+                legend_y,
+                colour,
+                escape(name)
+            );
+        }
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+fn format_tick(value: f32) -> String {
+    if value.abs() >= 100.0 || value.fract() == 0.0 {
+        format!("{:.0}", value)
+    } else {
+        format!("{:.2}", value)
+    }
+}
+
+fn dose_table(dvhs: &[Dvh]) -> String {
+    let mut html = String::from(
+        "<table><tr><th>Structure</th><th>Voxels</th><th>Min</th><th>Mean</th>\
+         <th>Max</th><th>D95</th><th>D2</th><th>V100%</th></tr>",
+    );
+    for dvh in dvhs {
+        let coverage = match dvh.prescription {
+            Some(prescription) => format!("{:.3}", dvh.volume_at_dose(prescription)),
+            None => "-".to_string(),
+        };
+        let _ = write!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{:.2}</td><td>{:.2}</td><td>{:.2}</td>\
+             <td>{:.2}</td><td>{:.2}</td><td>{}</td></tr>",
+            escape(&dvh.name),
+            dvh.doses.len(),
+            dvh.min(),
+            dvh.mean(),
+            dvh.max(),
+            dvh.dose_at_volume(0.95),
+            dvh.dose_at_volume(0.02),
+            coverage
+        );
+    }
+    html.push_str("</table>");
+    html
+}
+
+// Single HTML page with everything inlined, slice images as base64 PNGs
+// and charts as SVG, so the file can be read offline on its own
+pub fn plan_report(plan: &Plan, title: &str) -> io::Result<String> {
+    let labels = plan.labels()?;
+    let dose_params = plan
+        .indv()
+        .compute_dose(&plan.patient, &plan.targets, &labels);
+    let costs = compute_cost_breakdown(&dose_params, &labels, &plan.objectives);
+    let beam_count_cost = compute_beam_count_cost(plan.beams.len(), &plan.objectives);
+    let dose = dose_params.dose_matrix;
+    let index_cost = compute_index_cost(&dose, &labels, &plan.objectives);
+    let dvhs = plan.dvhs(&dose);
+    let objectives = &plan.objectives;
+
+    let mut html = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{title}</title>\
+         <style>{STYLE}</style></head><body><h1>{title}</h1>\
+         <p>{} beams, fitness {} ({:?} delivery, {} generations of {})</p>",
+        plan.beams.len(),
+        plan.fitness,
+        plan.config.delivery,
+        plan.config.generations,
+        plan.config.population_size,
+        title = escape(title),
+    );
+
+    if !plan.convergence.is_empty() {
+        html.push_str("<h2>Convergence</h2>");
+        html.push_str(&line_chart(
+            &[(
+                "Best fitness".to_string(),
+                plan.convergence
+                    .iter()
+                    .enumerate()
+                    .map(|(generation, fitness)| (generation as f32, *fitness))
+                    .collect(),
+            )],
+            "Generation",
+            "Best fitness",
+        ));
+    }
+
+    html.push_str(
+        "<h2>Cost breakdown</h2><table><tr><th>Term</th><th>Cost</th>\
+         <th>Weight</th><th>Weighted</th></tr>",
+    );
+    let terms = [
+        ("Tumour", costs.tumour, objectives.weight_tumour),
+        ("Serial organs", costs.serial, objectives.weight_serial),
+        (
+            "Parallel organs",
+            costs.parallel,
+            objectives.weight_parallel,
+        ),
+        ("Healthy tissue", costs.healthy, objectives.weight_healthy),
+        (
+            "Beam count",
+            plan.beams.len() as f32,
+            objectives.weight_beam_count,
+        ),
+    ];
+    for (name, cost, weight) in terms {
+        let _ = write!(
+            html,
+            "<tr><td>{}</td><td>{:.3}</td><td>{}</td><td>{:.3}</td></tr>",
+            name,
+            cost,
+            weight,
+            cost * weight
+        );
+    }
+    if objectives.uses_indices() {
+        let _ = write!(
+            html,
+            "<tr><td>Quality indices</td><td></td><td></td><td>{:.3}</td></tr>",
+            index_cost
+        );
+    }
+    let _ = write!(
+        html,
+        "<tr><th>Total</th><td></td><td></td><th>{:.3}</th></tr></table>",
+        costs.total(objectives) + beam_count_cost + index_cost
+    );
+
+    html.push_str("<h2>Dose volume histograms</h2>");
+    let bin_width = (dvhs.iter().map(|dvh| dvh.max()).fold(0.0, f32::max) / 200.0).max(1e-3);
+    let curves: Vec<(String, Vec<(f32, f32)>)> = dvhs
+        .iter()
+        .map(|dvh| {
+            let curve = dvh.curve(bin_width);
+            (
+                dvh.name.clone(),
+                curve
+                    .into_iter()
+                    .map(|(dose, volume)| (dose, volume * 100.0))
+                    .collect(),
+            )
+        })
+        .collect();
+    html.push_str(&line_chart(&curves, "Dose", "Volume %"));
+    html.push_str("<h2>Dose statistics</h2>");
+    html.push_str(&dose_table(&dvhs));
+    html.push_str(
+        "<h2>Plan quality</h2><table><tr><th>Target</th><th>RTOG CI</th>\
+         <th>Paddick CI</th><th>HI</th><th>GI</th></tr>",
+    );
+    for (name, indices) in plan.indices(&dose)? {
+        let _ = write!(
+            html,
+            "<tr><td>{}</td><td>{:.3}</td><td>{:.3}</td><td>{:.3}</td><td>{:.3}</td></tr>",
+            escape(&name),
+            indices.rtog_ci,
+            indices.paddick_ci,
+            indices.homogeneity,
+            indices.gradient
+        );
+    }
+    html.push_str("</table>");
+
+    html.push_str("<h2>Slices</h2><div>");
+    let options = RenderOptions {
+        reference_dose: objectives.default_prescription,
+        ..RenderOptions::default()
+    };
+    let centre = plan.view_centre();
+    for axis in SliceAxis::iter() {
+        let slice = axis.slice_through(&plan.patient, &centre);
+        let image = render_slice(
+            &plan.patient,
+            &dose,
+            &plan.structures,
+            axis,
+            slice,
+            &options,
+        );
+        let _ = write!(
+            html,
+            "<img alt=\"{axis} slice {slice}\" title=\"{axis} slice {slice}\" \
+             src=\"data:image/png;base64,{}\">",
+            base64(&image.encode_png()?),
+        );
+    }
+    html.push_str("</div></body></html>\n");
+    Ok(html)
+}
+
+pub fn write_report(path: &Path, plan: &Plan, title: &str) -> io::Result<()> {
+    fs::write(path, plan_report(plan, title)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beam_utils::{Beam, Objectives, PatientBox, Target, TissueBox, TissueType};
+    use crate::ga::{DeliveryMode, GaConfig};
+    use crate::labels::OverlapPriority;
+    use crate::primitives::{Cylinder, Sphere, voxelise};
+    use crate::vector::Vector;
+
+    #[test]
+    fn test_base64_and_chart() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+
+        let chart = line_chart(
+            &[
+                ("PTV".to_string(), vec![(0.0, 100.0), (40.0, 0.0)]),
+                ("Cord <1>".to_string(), vec![(0.0, 100.0), (10.0, 0.0)]),
+            ],
+            "Dose",
+            "Volume %",
+        );
+        assert!(chart.starts_with("<svg"));
+        assert_eq!(chart.matches("<polyline").count(), 2);
+        assert!(chart.contains("Cord &lt;1&gt;"));
+    }
+
+    #[test]
+    fn test_plan_report() {
+        let patient = PatientBox::new(16, 16, 16);
+        let targets = vec![Target {
+            tissue: TissueBox {
+                x: 8,
+                y: 8,
+                z: 8,
+                x_width: 4,
+                y_width: 4,
+                z_width: 4,
+                tissue_type: Some(TissueType::Tumour),
+            },
+            prescription: 40.0,
+        }];
+        let mut tumour = voxelise(
+            &Sphere {
+                centre: Vector::new(8.0, 8.0, 8.0),
+                radius: 3.0,
+            },
+            &patient,
+            TissueType::Tumour,
+        );
+        tumour.prescription = Some(40.0);
+        let cord = voxelise(
+            &Cylinder {
+                start: Vector::new(8.0, 13.0, 0.0),
+                end: Vector::new(8.0, 13.0, 15.0),
+                radius: 1.5,
+            },
+            &patient,
+            TissueType::SerialOrgan,
+        );
+        let plan = Plan {
+            patient,
+            targets,
+            structures: vec![tumour, cord],
+            structure_names: vec!["PTV".to_string(), "Cord".to_string()],
+            body: None,
+            overlap_priority: OverlapPriority::TargetWins,
+            attenuation: None,
+            objectives: Objectives {
+                weight_paddick_ci: 1.0,
+                ..Objectives::default()
+            },
+            beams: vec![
+                Beam::Static {
+                    entry: Vector::new(0.0, 8.0, 8.0),
+                    target: 0,
+                },
+                Beam::Static {
+                    entry: Vector::new(8.0, 0.0, 8.0),
+                    target: 0,
+                },
+            ],
+            config: GaConfig {
+                population_size: 4,
+                generations: 2,
+                tournament_size: 2,
+                delivery: DeliveryMode::Static,
+            },
+            fitness: 0.0,
+            convergence: vec![12.0, 10.0],
+        };
+
+        let html = plan_report(&plan, "Phantom").unwrap();
+        // The cost table adds up to the fitness the optimiser would give
+        let total = plan.evaluate().unwrap();
+        assert!(total > 0.0);
+        assert!(html.contains(&format!("<th>{:.3}</th></tr></table>", total)));
+        let dvh_section = &html[html.find("<h2>Dose volume histograms</h2>").unwrap()
+            ..html.find("<h2>Dose statistics</h2>").unwrap()];
+        assert_eq!(
+            dvh_section.matches("<polyline").count(),
+            plan.structures.len()
+        );
+        assert_eq!(html.matches("data:image/png;base64,").count(), 3);
+    }
+}
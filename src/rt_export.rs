@@ -0,0 +1,459 @@
+use crate::beam_utils::{Beam, PatientBox, Target};
+use crate::dicom::{
+    BITS_ALLOCATED, COLUMNS, DicomWriter, FRAME_OF_REFERENCE_UID, IMAGE_ORIENTATION,
+    IMAGE_POSITION, MODALITY, PIXEL_DATA, PIXEL_REPRESENTATION, PIXEL_SPACING, ROWS,
+    SERIES_INSTANCE_UID, STUDY_INSTANCE_UID, Tag, new_uid,
+};
+use crate::ga::Indv;
+use crate::nifti::Anatomy;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const RT_PLAN_STORAGE: &str = "1.2.840.10008.5.1.4.1.1.481.5";
+const RT_DOSE_STORAGE: &str = "1.2.840.10008.5.1.4.1.1.481.2";
+
+const SOP_CLASS_UID: Tag = (0x0008, 0x0016);
+const SOP_INSTANCE_UID: Tag = (0x0008, 0x0018);
+const REFERENCED_SOP_CLASS_UID: Tag = (0x0008, 0x1150);
+const REFERENCED_SOP_INSTANCE_UID: Tag = (0x0008, 0x1155);
+const PATIENT_NAME: Tag = (0x0010, 0x0010);
+const PATIENT_ID: Tag = (0x0010, 0x0020);
+const PATIENT_POSITION: Tag = (0x0018, 0x5100);
+const SAMPLES_PER_PIXEL: Tag = (0x0028, 0x0002);
+const PHOTOMETRIC_INTERPRETATION: Tag = (0x0028, 0x0004);
+const NUMBER_OF_FRAMES: Tag = (0x0028, 0x0008);
+const FRAME_INCREMENT_POINTER: Tag = (0x0028, 0x0009);
+const BITS_STORED: Tag = (0x0028, 0x0101);
+const HIGH_BIT: Tag = (0x0028, 0x0102);
+const DOSE_UNITS: Tag = (0x3004, 0x0002);
+const DOSE_TYPE: Tag = (0x3004, 0x0004);
+const DOSE_SUMMATION_TYPE: Tag = (0x3004, 0x000A);
+const GRID_FRAME_OFFSET_VECTOR: Tag = (0x3004, 0x000C);
+const DOSE_GRID_SCALING: Tag = (0x3004, 0x000E);
+const RT_PLAN_LABEL: Tag = (0x300A, 0x0002);
+const RT_PLAN_GEOMETRY: Tag = (0x300A, 0x000C);
+const FRACTION_GROUP_SEQUENCE: Tag = (0x300A, 0x0070);
+const FRACTION_GROUP_NUMBER: Tag = (0x300A, 0x0071);
+const NUMBER_OF_FRACTIONS_PLANNED: Tag = (0x300A, 0x0078);
+const NUMBER_OF_BEAMS: Tag = (0x300A, 0x0080);
+const BEAM_METERSET: Tag = (0x300A, 0x0086);
+const BEAM_SEQUENCE: Tag = (0x300A, 0x00B0);
+const BEAM_NUMBER: Tag = (0x300A, 0x00C0);
+const BEAM_NAME: Tag = (0x300A, 0x00C2);
+const BEAM_TYPE: Tag = (0x300A, 0x00C4);
+const RADIATION_TYPE: Tag = (0x300A, 0x00C6);
+const TREATMENT_DELIVERY_TYPE: Tag = (0x300A, 0x00CE);
+const NUMBER_OF_WEDGES: Tag = (0x300A, 0x00D0);
+const NUMBER_OF_COMPENSATORS: Tag = (0x300A, 0x00E0);
+const NUMBER_OF_BOLI: Tag = (0x300A, 0x00ED);
+const NUMBER_OF_BLOCKS: Tag = (0x300A, 0x00F0);
+const FINAL_CUMULATIVE_METERSET_WEIGHT: Tag = (0x300A, 0x010E);
+const NUMBER_OF_CONTROL_POINTS: Tag = (0x300A, 0x0110);
+const CONTROL_POINT_SEQUENCE: Tag = (0x300A, 0x0111);
+const CONTROL_POINT_INDEX: Tag = (0x300A, 0x0112);
+const GANTRY_ANGLE: Tag = (0x300A, 0x011E);
+const GANTRY_ROTATION_DIRECTION: Tag = (0x300A, 0x011F);
+const PATIENT_SUPPORT_ANGLE: Tag = (0x300A, 0x0122);
+const ISOCENTER_POSITION: Tag = (0x300A, 0x012C);
+const CUMULATIVE_METERSET_WEIGHT: Tag = (0x300A, 0x0134);
+const PATIENT_SETUP_SEQUENCE: Tag = (0x300A, 0x0180);
+const PATIENT_SETUP_NUMBER: Tag = (0x300A, 0x0182);
+const REFERENCED_RT_PLAN_SEQUENCE: Tag = (0x300C, 0x0002);
+const REFERENCED_BEAM_SEQUENCE: Tag = (0x300C, 0x0004);
+const REFERENCED_BEAM_NUMBER: Tag = (0x300C, 0x0006);
+const REFERENCED_PATIENT_SETUP_NUMBER: Tag = (0x300C, 0x006A);
+
+// Largest stored dose value, leaves headroom in the 32 bit pixels
+const MAX_DOSE_PIXEL: f64 = 1e9;
+
+// Identifiers shared by the plan and dose objects of one export so
+// viewers can link them to each other and to the planning CT
+#[derive(Debug, Clone)]
+pub struct RtExport {
+    pub patient_name: String,
+    pub patient_id: String,
+    pub study_uid: String,
+    pub frame_of_reference_uid: String,
+    pub plan_uid: String,
+}
+
+impl RtExport {
+    pub fn new(patient_name: &str, patient_id: &str) -> RtExport {
+        RtExport {
+            patient_name: patient_name.to_string(),
+            patient_id: patient_id.to_string(),
+            study_uid: new_uid(),
+            frame_of_reference_uid: new_uid(),
+            plan_uid: new_uid(),
+        }
+    }
+
+    // Puts the export in the study and frame of reference of the planning
+    // CT, UIDs the anatomy doesn't have stay newly generated
+    pub fn with_anatomy(mut self, anatomy: &Anatomy) -> RtExport {
+        if let Some(study_uid) = &anatomy.study_uid {
+            self.study_uid = study_uid.clone();
+        }
+        if let Some(frame_of_reference_uid) = &anatomy.frame_of_reference_uid {
+            self.frame_of_reference_uid = frame_of_reference_uid.clone();
+        }
+        self
+    }
+
+    fn common(&self, sop_class: &str, sop_instance: &str, modality: &str) -> DicomWriter {
+        let mut writer = DicomWriter::default();
+        writer
+            .string(SOP_CLASS_UID, b"UI", sop_class)
+            .string(SOP_INSTANCE_UID, b"UI", sop_instance)
+            .string(MODALITY, b"CS", modality)
+            .string(PATIENT_NAME, b"PN", &self.patient_name)
+            .string(PATIENT_ID, b"LO", &self.patient_id)
+            .string(STUDY_INSTANCE_UID, b"UI", &self.study_uid)
+            .string(SERIES_INSTANCE_UID, b"UI", &new_uid())
+            .string(FRAME_OF_REFERENCE_UID, b"UI", &self.frame_of_reference_uid);
+        writer
+    }
+
+    // Each beam becomes a DICOM beam whose control points carry the IEC
+    // 61217 gantry and couch angles the beam was planned with, for a head
+    // first supine patient in the grid's frame.
+    // Control point weights become the beam meterset.
+    pub fn encode_rt_plan(&self, indv: &Indv, targets: &[Target], patient: &PatientBox) -> Vec<u8> {
+        let mut beams = Vec::new();
+        let mut referenced_beams = Vec::new();
+        for (idx, beam) in indv.beams.iter().enumerate() {
+            let control_points = beam.control_points(targets, patient);
+            let meterset: f32 = control_points.iter().map(|point| point.weight).sum();
+            let is_arc = matches!(beam, Beam::Arc { .. });
+            // A static field is a single segment between two control
+            // points, an arc spreads each point's weight over the segments
+            // either side of it, scaled so the beam delivers its meterset
+            let segments: Vec<_> = if is_arc {
+                control_points.iter().collect()
+            } else {
+                vec![&control_points[0], &control_points[0]]
+            };
+            let mut cumulative = vec![0.0f32];
+            for pair in segments.windows(2) {
+                let previous = cumulative[cumulative.len() - 1];
+                cumulative.push(previous + (pair[0].weight + pair[1].weight) / 2.0);
+            }
+            let total = cumulative[cumulative.len() - 1];
+            let cumulative: Vec<f32> = cumulative
+                .iter()
+                .map(|value| {
+                    if total > 0.0 {
+                        value * meterset / total
+                    } else {
+                        0.0
+                    }
+                })
+                .collect();
+
+            // Arcs keep the gantry and couch angles they were planned with,
+            // a static field's angles come from its direction
+            let angles: Vec<(f32, f32)> = match beam {
+                Beam::Arc { arc, .. } => arc
+                    .gantry_angles()
+                    .into_iter()
+                    .map(|gantry| (gantry, arc.couch_angle))
+                    .collect(),
+                Beam::Static { .. } => {
+                    let mut direction = segments[0].entry;
+                    direction.calculate_offset(&segments[0].aim);
+                    vec![direction.gantry_couch(); segments.len()]
+                }
+            };
+            let mut items = Vec::new();
+            for (cp_idx, point) in segments.iter().enumerate() {
+                let (gantry, couch) = angles[cp_idx];
+                let mut item = DicomWriter::default();
+                item.integer(CONTROL_POINT_INDEX, cp_idx)
+                    .decimals(CUMULATIVE_METERSET_WEIGHT, &[cumulative[cp_idx]])
+                    .decimals(GANTRY_ANGLE, &[gantry]);
+                // Arcs always rotate clockwise from start to stop, later
+                // control points only carry the angle that changes
+                if cp_idx == 0 {
+                    let iso = point.aim;
+                    item.string(
+                        GANTRY_ROTATION_DIRECTION,
+                        b"CS",
+                        if is_arc { "CW" } else { "NONE" },
+                    )
+                    .decimals(PATIENT_SUPPORT_ANGLE, &[couch.rem_euclid(360.0)])
+                    .decimals(
+                        ISOCENTER_POSITION,
+                        &[
+                            iso.x + patient.origin.x,
+                            iso.y + patient.origin.y,
+                            iso.z + patient.origin.z,
+                        ],
+                    );
+                }
+                items.push(item);
+            }
+
+            let mut beam_item = DicomWriter::default();
+            beam_item
+                .integer(BEAM_NUMBER, idx + 1)
+                .string(BEAM_NAME, b"LO", &format!("Beam {}", idx + 1))
+                .string(BEAM_TYPE, b"CS", if is_arc { "DYNAMIC" } else { "STATIC" })
+                .string(RADIATION_TYPE, b"CS", "PHOTON")
+                .string(TREATMENT_DELIVERY_TYPE, b"CS", "TREATMENT")
+                .integer(NUMBER_OF_WEDGES, 0)
+                .integer(NUMBER_OF_COMPENSATORS, 0)
+                .integer(NUMBER_OF_BOLI, 0)
+                .integer(NUMBER_OF_BLOCKS, 0)
+                .integer(REFERENCED_PATIENT_SETUP_NUMBER, 1)
+                .decimals(FINAL_CUMULATIVE_METERSET_WEIGHT, &[meterset])
+                .integer(NUMBER_OF_CONTROL_POINTS, items.len())
+                .sequence(CONTROL_POINT_SEQUENCE, &items);
+            beams.push(beam_item);
+
+            let mut referenced = DicomWriter::default();
+            referenced
+                .integer(REFERENCED_BEAM_NUMBER, idx + 1)
+                .decimals(BEAM_METERSET, &[meterset]);
+            referenced_beams.push(referenced);
+        }
+
+        let mut fraction_group = DicomWriter::default();
+        fraction_group
+            .integer(FRACTION_GROUP_NUMBER, 1)
+            .integer(NUMBER_OF_FRACTIONS_PLANNED, 1)
+            .integer(NUMBER_OF_BEAMS, beams.len())
+            .sequence(REFERENCED_BEAM_SEQUENCE, &referenced_beams);
+
+        let mut setup = DicomWriter::default();
+        setup
+            .string(PATIENT_POSITION, b"CS", "HFS")
+            .integer(PATIENT_SETUP_NUMBER, 1);
+
+        let mut plan = self.common(RT_PLAN_STORAGE, &self.plan_uid, "RTPLAN");
+        plan.string(RT_PLAN_LABEL, b"SH", "Optimised")
+            .string(RT_PLAN_GEOMETRY, b"CS", "PATIENT")
+            .sequence(PATIENT_SETUP_SEQUENCE, &[setup])
+            .sequence(FRACTION_GROUP_SEQUENCE, &[fraction_group])
+            .sequence(BEAM_SEQUENCE, &beams);
+        plan.to_part10(RT_PLAN_STORAGE, &self.plan_uid)
+    }
+
+    // Multi-frame dose in Gy, one frame per z slice of the grid. Rows
+    // and columns are 16 bit, wider grids are an error.
+    pub fn encode_rt_dose(&self, patient: &PatientBox, dose: &[f32]) -> io::Result<Vec<u8>> {
+        if let Some(size) = [patient.x_size, patient.y_size]
+            .iter()
+            .find(|size| !(1..=u16::MAX as i64).contains(*size))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "dose grid size {} doesn't fit the 16 bit RT Dose rows and columns",
+                    size
+                ),
+            ));
+        }
+        let max_dose = dose.iter().cloned().fold(0.0f32, f32::max) as f64;
+        let scaling_text = if max_dose > 0.0 {
+            format!("{:.6e}", max_dose / MAX_DOSE_PIXEL)
+        } else {
+            "1".to_string()
+        };
+        let scaling: f64 = scaling_text.parse().unwrap();
+        let pixels: Vec<u8> = dose
+            .iter()
+            .flat_map(|value| ((value.max(0.0) as f64 / scaling).round() as u32).to_le_bytes())
+            .collect();
+        let offsets: Vec<f32> = (0..patient.z_size)
+            .map(|z| z as f32 * patient.spacing.z)
+            .collect();
+
+        let mut referenced_plan = DicomWriter::default();
+        referenced_plan
+            .string(REFERENCED_SOP_CLASS_UID, b"UI", RT_PLAN_STORAGE)
+            .string(REFERENCED_SOP_INSTANCE_UID, b"UI", &self.plan_uid);
+
+        let dose_uid = new_uid();
+        let mut rt_dose = self.common(RT_DOSE_STORAGE, &dose_uid, "RTDOSE");
+        rt_dose
+            .decimals(
+                IMAGE_POSITION,
+                &[patient.origin.x, patient.origin.y, patient.origin.z],
+            )
+            .decimals(IMAGE_ORIENTATION, &[1.0, 0.0, 0.0, 0.0, 1.0, 0.0])
+            .u16(SAMPLES_PER_PIXEL, 1)
+            .string(PHOTOMETRIC_INTERPRETATION, b"CS", "MONOCHROME2")
+            .integer(NUMBER_OF_FRAMES, patient.z_size as usize)
+            .element(FRAME_INCREMENT_POINTER, b"AT", &[0x04, 0x30, 0x0C, 0x00])
+            .u16(ROWS, patient.y_size as u16)
+            .u16(COLUMNS, patient.x_size as u16)
+            .decimals(PIXEL_SPACING, &[patient.spacing.y, patient.spacing.x])
+            .u16(BITS_ALLOCATED, 32)
+            .u16(BITS_STORED, 32)
+            .u16(HIGH_BIT, 31)
+            .u16(PIXEL_REPRESENTATION, 0)
+            .string(DOSE_UNITS, b"CS", "GY")
+            .string(DOSE_TYPE, b"CS", "PHYSICAL")
+            .string(DOSE_SUMMATION_TYPE, b"CS", "PLAN")
+            .decimals(GRID_FRAME_OFFSET_VECTOR, &offsets)
+            .string(DOSE_GRID_SCALING, b"DS", &scaling_text)
+            .sequence(REFERENCED_RT_PLAN_SEQUENCE, &[referenced_plan])
+            .element(PIXEL_DATA, b"OW", &pixels);
+        Ok(rt_dose.to_part10(RT_DOSE_STORAGE, &dose_uid))
+    }
+
+    pub fn write_rt_plan(
+        &self,
+        path: &Path,
+        indv: &Indv,
+        targets: &[Target],
+        patient: &PatientBox,
+    ) -> io::Result<()> {
+        fs::write(path, self.encode_rt_plan(indv, targets, patient))
+    }
+
+    pub fn write_rt_dose(&self, path: &Path, patient: &PatientBox, dose: &[f32]) -> io::Result<()> {
+        fs::write(path, self.encode_rt_dose(patient, dose)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arc::GantryArc;
+    use crate::beam_utils::{TissueBox, TissueType};
+    use crate::dicom::parse_dicom;
+    use crate::vector::Vector;
+
+    #[test]
+    fn test_rt_plan_and_dose() {
+        let patient = PatientBox::new(20, 20, 20).with_origin(Vector::new(-10.0, -10.0, 0.0));
+        let targets = vec![Target {
+            tissue: TissueBox {
+                x: 10,
+                y: 10,
+                z: 10,
+                x_width: 4,
+                y_width: 4,
+                z_width: 4,
+                tissue_type: Some(TissueType::Tumour),
+            },
+            prescription: 40.0,
+        }];
+        // Known fields of a head first supine patient around the
+        // isocentre at (10, 10, 10) with their IEC gantry and couch angles
+        let fields = [
+            (Vector::new(10.0, 0.0, 10.0), 0.0, 0.0),     // AP, anterior
+            (Vector::new(10.0, 20.0, 10.0), 180.0, 0.0),  // PA, posterior
+            (Vector::new(20.0, 10.0, 10.0), 90.0, 0.0),   // left lateral
+            (Vector::new(0.0, 10.0, 10.0), 270.0, 0.0),   // right lateral
+            (Vector::new(20.0, 10.0, 20.0), 90.0, 315.0), // left superior oblique
+        ];
+        let mut beams: Vec<Beam> = fields
+            .iter()
+            .map(|(entry, _, _)| Beam::Static {
+                entry: *entry,
+                target: 0,
+            })
+            .collect();
+        beams.push(Beam::Arc {
+            arc: GantryArc::new(0.0, 40.0, 10.0, 30.0).unwrap(),
+            target: 0,
+        });
+        let ga_arc = GantryArc::random();
+        beams.push(Beam::Arc {
+            arc: ga_arc.clone(),
+            target: 0,
+        });
+        let indv = Indv {
+            beams,
+            fitness: 0.0,
+        };
+        // The export joins the planning CT's study and frame of reference
+        let anatomy = Anatomy {
+            patient_box: patient.clone(),
+            hounsfield: vec![],
+            masks: vec![],
+            study_uid: Some("1.2.2".to_string()),
+            frame_of_reference_uid: Some("1.2.4".to_string()),
+        };
+        let export = RtExport::new("Phantom", "0001").with_anatomy(&anatomy);
+
+        let plan = parse_dicom(&export.encode_rt_plan(&indv, &targets, &patient)).unwrap();
+        assert_eq!(plan.string(STUDY_INSTANCE_UID), Some("1.2.2".to_string()));
+        assert_eq!(
+            plan.string(FRAME_OF_REFERENCE_UID),
+            Some("1.2.4".to_string())
+        );
+        assert_eq!(
+            plan.sequence(PATIENT_SETUP_SEQUENCE)[0].string(PATIENT_POSITION),
+            Some("HFS".to_string())
+        );
+        let beams = plan.sequence(BEAM_SEQUENCE);
+        assert_eq!(beams.len(), 7);
+        for (beam, (_, gantry, couch)) in beams.iter().zip(fields) {
+            let static_points = beam.sequence(CONTROL_POINT_SEQUENCE);
+            assert_eq!(static_points.len(), 2);
+            let point = &static_points[0];
+            assert!((point.number(GANTRY_ANGLE).unwrap() - gantry).abs() < 1e-3);
+            assert!((point.number(PATIENT_SUPPORT_ANGLE).unwrap() - couch).abs() < 1e-3);
+        }
+        assert_eq!(
+            beams[0].sequence(CONTROL_POINT_SEQUENCE)[0].numbers(ISOCENTER_POSITION),
+            vec![0.0, 0.0, 10.0]
+        );
+        let arc_points = beams[5].sequence(CONTROL_POINT_SEQUENCE);
+        assert_eq!(arc_points.len(), 5);
+        assert_eq!(arc_points[4].number(CUMULATIVE_METERSET_WEIGHT), Some(1.0));
+        assert!(arc_points[0].number(PATIENT_SUPPORT_ANGLE).is_some());
+
+        // An optimised arc is delivered on one couch angle, the one it was
+        // planned and collision checked with, in the plane its control
+        // points were computed in
+        let arc_points = beams[6].sequence(CONTROL_POINT_SEQUENCE);
+        let arc_couch = ga_arc.couch_angle.rem_euclid(360.0) as f64;
+        assert_eq!(
+            arc_points[0].string(GANTRY_ROTATION_DIRECTION),
+            Some("CW".to_string())
+        );
+        assert!((arc_points[0].number(PATIENT_SUPPORT_ANGLE).unwrap() - arc_couch).abs() < 1e-3);
+        let planned = ga_arc.control_points(&targets[0].tissue, &patient);
+        assert_eq!(arc_points.len(), planned.len());
+        for ((point, gantry), control_point) in
+            arc_points.iter().zip(ga_arc.gantry_angles()).zip(&planned)
+        {
+            if let Some(couch) = point.number(PATIENT_SUPPORT_ANGLE) {
+                assert!((couch - arc_couch).abs() < 1e-3);
+            }
+            assert!((point.number(GANTRY_ANGLE).unwrap() - gantry as f64).abs() < 1e-3);
+            if gantry.to_radians().sin().abs() > 0.1 {
+                let mut direction = control_point.entry;
+                direction.calculate_offset(&control_point.aim);
+                let (g, c) = direction.gantry_couch();
+                assert!((g - gantry).abs() < 1e-2, "gantry {} != {}", g, gantry);
+                assert!(
+                    (c - ga_arc.couch_angle).abs() < 1e-2,
+                    "couch {} != {}",
+                    c,
+                    ga_arc.couch_angle
+                );
+            }
+        }
+
+        let dose: Vec<f32> = (0..patient.grid_size()).map(|i| i as f32 * 0.01).collect();
+        let rt_dose = parse_dicom(&export.encode_rt_dose(&patient, &dose).unwrap()).unwrap();
+        assert_eq!(
+            rt_dose.string(FRAME_OF_REFERENCE_UID),
+            Some("1.2.4".to_string())
+        );
+        let scaling = rt_dose.number(DOSE_GRID_SCALING).unwrap();
+        let pixels = rt_dose.bytes(PIXEL_DATA).unwrap();
+        let last = u32::from_le_bytes(pixels[pixels.len() - 4..].try_into().unwrap());
+        assert!((last as f64 * scaling - 79.99).abs() < 1e-4);
+        assert_eq!(rt_dose.numbers(GRID_FRAME_OFFSET_VECTOR).len(), 20);
+        // Rows and columns past 16 bits are refused, not wrapped
+        let wide = PatientBox::new(70_000, 1, 1);
+        assert!(export.encode_rt_dose(&wide, &vec![0.0; 70_000]).is_err());
+    }
+}
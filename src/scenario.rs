@@ -0,0 +1,250 @@
+use crate::beam_utils::{Objectives, PatientBox, Target, TissueBox, TissueType};
+use crate::collision::CollisionModel;
+use crate::ga::{GaConfig, GaResult};
+use crate::labels::{LabelVolume, OverlapPriority};
+use crate::mask::{Mask, VoxelMask};
+use crate::mesh::{Mesh, read_mesh, voxelise_mesh};
+use crate::nifti::invalid_data;
+use crate::plan::Plan;
+use crate::primitives::{Cylinder, Ellipsoid, Sphere, voxelise};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Geometry of a scenario structure in mm from the grid corner, only
+// meshes are placed in scanner coordinates
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "shape", rename_all = "snake_case")]
+pub enum Shape {
+    Box {
+        x: i64,
+        y: i64,
+        z: i64,
+        x_width: i64,
+        y_width: i64,
+        z_width: i64,
+    },
+    Sphere(Sphere),
+    Ellipsoid(Ellipsoid),
+    Cylinder(Cylinder),
+    // Closed STL or OBJ surface in scanner coordinates, `file` is
+    // relative to the scenario and read by load_scenario
+    Mesh {
+        file: PathBuf,
+        #[serde(skip)]
+        mesh: Mesh,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScenarioStructure {
+    pub name: String,
+    pub tissue_type: TissueType,
+    #[serde(default)]
+    pub prescription: Option<f32>,
+    #[serde(flatten)]
+    pub shape: Shape,
+}
+
+impl ScenarioStructure {
+    fn tissue_box(&self) -> Option<TissueBox> {
+        match self.shape {
+            Shape::Box {
+                x,
+                y,
+                z,
+                x_width,
+                y_width,
+                z_width,
+            } => Some(TissueBox {
+                x,
+                y,
+                z,
+                x_width,
+                y_width,
+                z_width,
+                tissue_type: Some(self.tissue_type.clone()),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn mask(&self, p_box: &PatientBox) -> VoxelMask {
+        let mut mask = match &self.shape {
+            Shape::Box { .. } => VoxelMask::from_mask(
+                &Mask::from_tissue_box(&self.tissue_box().unwrap(), p_box),
+                p_box,
+            ),
+            Shape::Sphere(sphere) => voxelise(sphere, p_box, self.tissue_type.clone()),
+            Shape::Ellipsoid(ellipsoid) => voxelise(ellipsoid, p_box, self.tissue_type.clone()),
+            Shape::Cylinder(cylinder) => voxelise(cylinder, p_box, self.tissue_type.clone()),
+            Shape::Mesh { mesh, .. } => voxelise_mesh(mesh, p_box, self.tissue_type.clone()),
+        };
+        mask.prescription = self.prescription;
+        mask
+    }
+}
+
+// A planning case read from a JSON file, replaces the setup that used
+// to be hard-coded in main.rs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Scenario {
+    pub patient: PatientBox,
+    pub structures: Vec<ScenarioStructure>,
+    #[serde(default)]
+    pub overlap_priority: OverlapPriority,
+    #[serde(default)]
+    pub objectives: Objectives,
+    #[serde(default)]
+    pub collision: CollisionModel,
+    pub ga: GaConfig,
+}
+
+impl Scenario {
+    pub fn masks(&self) -> Vec<VoxelMask> {
+        self.structures
+            .iter()
+            .map(|structure| structure.mask(&self.patient))
+            .collect()
+    }
+
+    // Every tumour becomes a target with its own prescription or the
+    // default one, boxes are aimed at as given and other shapes at the
+    // centre of their voxel extent
+    pub fn targets(&self) -> Vec<Target> {
+        self.structures
+            .iter()
+            .filter(|structure| matches!(structure.tissue_type, TissueType::Tumour))
+            .filter_map(|structure| {
+                let tissue = structure
+                    .tissue_box()
+                    .or_else(|| structure.mask(&self.patient).bounding_box())?;
+                Some(Target {
+                    tissue,
+                    prescription: structure
+                        .prescription
+                        .unwrap_or(self.objectives.default_prescription),
+                })
+            })
+            .collect()
+    }
+
+    pub fn labels(&self) -> io::Result<LabelVolume> {
+        LabelVolume::from_structures(&self.masks(), &self.patient, &self.overlap_priority)
+    }
+
+    // Stores the optimised beams together with the voxelised scenario
+    pub fn plan(&self, result: &GaResult) -> Plan {
+        Plan {
+            patient: self.patient.clone(),
+            targets: self.targets(),
+            structures: self.masks(),
+            structure_names: self
+                .structures
+                .iter()
+                .map(|structure| structure.name.clone())
+                .collect(),
+            body: None,
+            overlap_priority: self.overlap_priority.clone(),
+            attenuation: None,
+            objectives: self.objectives.clone(),
+            beams: result.best.beams.clone(),
+            config: self.ga.clone(),
+            fitness: result.best.fitness,
+            convergence: result.convergence.clone(),
+        }
+    }
+}
+
+pub fn load_scenario(path: &Path) -> io::Result<Scenario> {
+    let mut scenario: Scenario = serde_json::from_str(&fs::read_to_string(path)?)?;
+    scenario.ga.validate()?;
+    scenario.patient.validate()?;
+    let directory = path.parent().unwrap_or(Path::new(""));
+    for structure in &mut scenario.structures {
+        if let Shape::Mesh { file, mesh } = &mut structure.shape {
+            *mesh = read_mesh(&directory.join(&file))?;
+        }
+    }
+    if scenario.targets().is_empty() {
+        return Err(invalid_data("scenario has no tumour inside the grid"));
+    }
+    // A box tumour is a target even when it lies off the grid, so every
+    // tumour must also cover at least one voxel
+    for structure in &scenario.structures {
+        if matches!(structure.tissue_type, TissueType::Tumour)
+            && structure.mask(&scenario.patient).count() == 0
+        {
+            return Err(invalid_data(format!(
+                "tumour {} has no voxels inside the grid",
+                structure.name
+            )));
+        }
+    }
+    Ok(scenario)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ga::DeliveryMode;
+
+    #[test]
+    fn test_parse_scenario() {
+        let json = r#"{
+            "patient": {
+                "x_size": 30, "y_size": 30, "z_size": 20,
+                "spacing": {"x": 1.0, "y": 1.0, "z": 2.0},
+                "origin": {"x": 0.0, "y": 0.0, "z": 0.0}
+            },
+            "structures": [
+                {"name": "PTV", "tissue_type": "Tumour", "prescription": 40.0,
+                 "shape": "sphere", "centre": {"x": 15.0, "y": 15.0, "z": 20.0}, "radius": 4.0},
+                {"name": "Cord", "tissue_type": "SerialOrgan",
+                 "shape": "box", "x": 15, "y": 25, "z": 20, "x_width": 2, "y_width": 2, "z_width": 30}
+            ],
+            "objectives": {
+                "weight_tumour": 1.0, "weight_serial": 2.0, "weight_parallel": 1.0,
+                "weight_healthy": 0.75, "weight_beam_count": 1.0, "default_prescription": 40.0,
+                "threshold_serial": 0.0, "threshold_parallel": 0.0, "threshold_healthy": 0.375
+            },
+            "ga": {"population_size": 10, "generations": 5, "tournament_size": 3, "delivery": "Arc"}
+        }"#;
+        let scenario: Scenario = serde_json::from_str(json).unwrap();
+        assert_eq!(scenario.ga.delivery, DeliveryMode::Arc);
+        assert_eq!(scenario.objectives.weight_serial, 2.0);
+        assert!(scenario.collision.regions.is_empty());
+
+        let targets = scenario.targets();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].tissue.centre().z, 20.0);
+        let labels = scenario.labels().unwrap();
+        assert_eq!(labels.structure_volume(1), scenario.masks()[1].count());
+
+        // Tumours without a prescription of their own use the default one
+        let mut scenario = scenario;
+        scenario.structures[0].prescription = None;
+        scenario.objectives.default_prescription = 50.0;
+        assert_eq!(scenario.targets()[0].prescription, 50.0);
+
+        // Without a tumour the optimiser has nothing to aim at
+        let path = std::env::temp_dir().join(format!("scenario_{}.json", std::process::id()));
+        fs::write(&path, json).unwrap();
+        assert!(load_scenario(&path).is_ok());
+        fs::write(&path, json.replace("\"Tumour\"", "\"ParallelOrgan\"")).unwrap();
+        assert!(load_scenario(&path).is_err());
+        // Nor with a tumour off the grid, or a grid without voxels
+        let off_grid = json
+            .replace("\"SerialOrgan\"", "\"Tumour\"")
+            .replace("\"x\": 15, \"y\": 25", "\"x\": 500, \"y\": 25");
+        let no_voxels = json.replace("\"x_size\": 30", "\"x_size\": 0");
+        let bad_spacing = json.replace("\"z\": 2.0}", "\"z\": -2.0}");
+        for broken in [off_grid, no_voxels, bad_spacing] {
+            assert_ne!(broken, json);
+            fs::write(&path, broken).unwrap();
+            assert!(load_scenario(&path).is_err());
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,7 +1,8 @@
 use crate::beam_utils::{PatientBox, TissueBox};
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct Vector {
     pub x: f32,
     pub y: f32,
@@ -9,7 +10,7 @@ pub struct Vector {
 }
 
 impl Vector {
-    pub fn new(x_in: f32, y_in: f32, z_in: f32) -> Vector {
+    pub const fn new(x_in: f32, y_in: f32, z_in: f32) -> Vector {
         Vector {
             x: x_in,
             y: y_in,
@@ -17,25 +18,64 @@ impl Vector {
         }
     }
 
+    // Unit direction from the isocentre towards the source for IEC 61217
+    // gantry and patient support angles in degrees. The grid is the DICOM
+    // patient frame (x to the patient's left, y posterior, z superior) of
+    // a head first supine patient, so gantry 0 is anterior, gantry 90 the
+    // patient's left and the couch turns the gantry plane about y.
+    pub fn from_gantry_couch(gantry: f32, couch: f32) -> Vector {
+        let (g, c) = (gantry.to_radians(), couch.to_radians());
+        Vector {
+            x: g.sin() * c.cos(),
+            y: -g.cos(),
+            z: -g.sin() * c.sin(),
+        }
+    }
+
+    // Inverse of from_gantry_couch. Directions without a superior-inferior
+    // part keep the couch at 0, otherwise it is folded into (-90, 90]. The
+    // gantry is returned in [0, 360).
+    pub fn gantry_couch(&self) -> (f32, f32) {
+        let sin_gantry = (self.x.powf(2.0) + self.z.powf(2.0)).sqrt();
+        if sin_gantry < 1e-6 * self.dist_to_beam() {
+            let gantry = if self.y <= 0.0 { 0.0 } else { 180.0 };
+            return (gantry, 0.0);
+        }
+        let (mut sin_gantry, mut couch) = (sin_gantry, 0.0f32);
+        if self.z.abs() >= 1e-6 * sin_gantry {
+            couch = (-self.z).atan2(self.x).to_degrees();
+        } else if self.x < 0.0 {
+            sin_gantry = -sin_gantry;
+        }
+        if couch > 90.0 {
+            couch -= 180.0;
+            sin_gantry = -sin_gantry;
+        } else if couch <= -90.0 {
+            couch += 180.0;
+            sin_gantry = -sin_gantry;
+        }
+        let gantry = sin_gantry.atan2(-self.y).to_degrees().rem_euclid(360.0);
+        (gantry, couch)
+    }
+
     pub fn dot(&self, v2: &Vector) -> f32 {
         self.x * v2.x + self.y * v2.y + self.z * v2.z
     }
 
     pub fn calculate_offset(&mut self, v2: &Vector) {
-        self.x = self.x - v2.x as f32;
-        self.y = self.y - v2.y as f32;
-        self.z = self.z - v2.z as f32;
+        self.x -= v2.x;
+        self.y -= v2.y;
+        self.z -= v2.z;
     }
 
     pub fn dist_to_beam(&self) -> f32 {
-        let dist: f32 = (self.x.powf(2.0) + self.y.powf(2.0) + self.z.powf(2.0)) as f32;
+        let dist: f32 = self.x.powf(2.0) + self.y.powf(2.0) + self.z.powf(2.0);
         dist.sqrt()
     }
 
     pub fn dist_to_vector(&self, v2: &Vector) -> f32 {
-        let dist: f32 = ((v2.x - self.x).powf(2.0)
-            + (v2.y - self.y).powf(2.0)
-            + (v2.z - self.z).powf(2.0)) as f32;
+        let dist: f32 =
+            (v2.x - self.x).powf(2.0) + (v2.y - self.y).powf(2.0) + (v2.z - self.z).powf(2.0);
         dist.sqrt()
     }
 
@@ -64,18 +104,18 @@ impl Vector {
     }
 
     pub fn mutate(&mut self, mutation_bound: f32, patient: &PatientBox) {
-        self.x = mutate_val(&self.x, mutation_bound, patient.x_size as f32);
-        self.y = mutate_val(&self.y, mutation_bound, patient.y_size as f32);
-        self.z = mutate_val(&self.z, mutation_bound, patient.z_size as f32);
+        let extent = patient.extent();
+        self.x = mutate_val(&self.x, mutation_bound, extent.x);
+        self.y = mutate_val(&self.y, mutation_bound, extent.y);
+        self.z = mutate_val(&self.z, mutation_bound, extent.z);
     }
 }
 
 fn mutate_val(val: &f32, max_bound: f32, upper_bound: f32) -> f32 {
     if *val != 0.0 {
-        let mut rng = rand::rng();
+        let mut rng = crate::random::rng();
         let draw: f32 = rng.random_range(-max_bound..max_bound);
-        let nv = (val + draw).max(0.0).min(upper_bound);
-        nv
+        (val + draw).max(0.0).min(upper_bound)
     } else {
         0.0
     }
@@ -93,6 +133,16 @@ fn crossover_val(x1: &f32, x2: &f32, alpha: f32) -> f32 {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_gantry_couch_round_trip() {
+        for (gantry, couch) in [(0.0, 0.0), (90.0, 0.0), (270.0, 30.0), (135.0, -45.0)] {
+            let direction = Vector::from_gantry_couch(gantry, couch);
+            let (g, c) = direction.gantry_couch();
+            assert!((g - gantry).abs() < 1e-3, "gantry {} != {}", g, gantry);
+            assert!((c - couch).abs() < 1e-3, "couch {} != {}", c, couch);
+        }
+    }
+
     #[test]
     fn test_crossover_val() {
         let ans = crossover_val(&3.0, &6.25, 0.5);